@@ -31,10 +31,12 @@
 //!
 //! * `path` (as `path = "foo.html"`): sets the path to the template file. The
 //!   path is interpreted as relative to the `templates` dir in the directory
-//!   where the originating crate's `Cargo.toml` resides. The file name
-//!   extension is used to infer an escape mode (see below). In web framework
-//!   integrations, the path's extension may also be used to infer the content
-//!   type of the resulting response. Cannot be used together with `source`.
+//!   where the originating crate's `Cargo.toml` resides, or to the directories
+//!   configured in `askama.toml` (see [Configuration](#configuration)). The
+//!   file name extension is used to infer an escape mode (see below). In web
+//!   framework integrations, the path's extension may also be used to infer
+//!   the content type of the resulting response. Cannot be used together
+//!   with `source`.
 //! * `source` (as `source = "{{ foo }}"`): directly sets the template source.
 //!   This can be useful for test cases or short templates. The generated path
 //!   is empty, which generally makes it impossible to refer to this template
@@ -48,11 +50,26 @@
 //!   or `all` for both. The requested data will be printed to stdout at
 //!   compile time.
 //! * `escape` (as `escape = "none"`): set the escape mode for expression
-//!   output; the currently implemented modes are `none` and `html`. Askama
-//!   infers the escape mode from the template file name (with `path`) or
-//!   specified extension (`ext`): if the extension is `html`, `htm` or `xml`,
-//!   the `html` escape mode is used; otherwise, no implicit escaping is done.
+//!   output; the built-in modes are `none`, `html`, `xml`, `js`, `css`,
+//!   `url`, `latex` and `shell`, and a custom escaper can be used by giving
+//!   the path to its type (see below). Askama infers the escape mode from
+//!   the template file name (with `path`) or specified extension (`ext`):
+//!   if the extension is `html` or `htm`, the `html` escape mode is used,
+//!   for `xml` the `xml` mode; otherwise, no implicit escaping is done.
+//!   More extensions can be mapped to escape modes in `askama.toml`.
 //!   Setting an escape mode explicitly overrides the inferred value.
+//! * `syntax` (as `syntax = "foo"`): parse the template with a custom syntax
+//!   defined in `askama.toml`, instead of the default syntax. Templates
+//!   extended, included or imported by the template use the syntax
+//!   configured for their extension, or the default syntax.
+//! * `in_out_dir` (as `path = "foo.html", in_out_dir`): look up the template
+//!   file in `OUT_DIR` instead of the template directories, for templates
+//!   generated by the crate's build script. Cannot be used together with
+//!   `source`.
+//! * `whitespace` (as `whitespace = "suppress"`): set to `suppress` to drop
+//!   whitespace around all tags and expressions, as if each of them had
+//!   the `-` markers described below, or to `preserve` for the default
+//!   behavior.
 //!
 //!
 //! ## Variables
@@ -65,6 +82,31 @@
 //! while `{{ user.name }}` will get the ``name`` field of the ``user``
 //! field from the template context.
 //!
+//! Identifiers follow the same rules as in Rust, so they may contain
+//! Unicode characters. Names that are Rust keywords, like `type`, must be
+//! written as raw identifiers (`{{ r#type }}`); using them without the
+//! `r#` prefix results in a compile-time error.
+//!
+//! Local variables are declared with `let`, and can be made mutable with
+//! `let mut`, so that they can be changed later on with `set`:
+//!
+//! ```text
+//! {% let mut total = 0 %}
+//! {% for item in items %}
+//!   {% set total = total + item.price %}
+//! {% endfor %}
+//! Total: {{ total }}
+//! ```
+//!
+//! To limit the scope of variables, use a `with` block. The variables
+//! defined by it (which can also be `mut`) are only available inside it:
+//!
+//! ```text
+//! {% with count = items.len(), mut shown = 0 %}
+//!   ...
+//! {% endwith %}
+//! ```
+//!
 //! ## Filters
 //!
 //! Values such as those obtained from variables can be post-processed
@@ -105,6 +147,9 @@
 //! includes only whitespace, whitespace suppression on either side will
 //! completely suppress that literal content.
 //!
+//! Whitespace around all delimiters can be suppressed with the `whitespace`
+//! option, either per template or for the whole crate in `askama.toml`.
+//!
 //! ## Template inheritance
 //!
 //! Template inheritance allows you to build a base template with common
@@ -134,6 +179,11 @@
 //! inheritance. Blocks can only be specified at the top level of a template,
 //! not inside `if`/`else` branches or in `for`-loop bodies.
 //!
+//! A block can be marked as required by writing `{% block content required %}`.
+//! Each template extending the base template (directly or through other
+//! templates) must then override it, otherwise the derive fails with an error
+//! naming the template and the missing block.
+//!
 //! ### Child template
 //!
 //! Here's an example child template:
@@ -158,15 +208,61 @@
 //! from another template. It will search for the base template relative to
 //! itself before looking relative to the template base directory. It will
 //! render the top-level content from the base template, and substitute
-//! blocks from the base template with those from the child template. The
-//! inheriting template context `struct` must have a field called `_parent` of
-//! the type used as the base template context. Blocks can refer to the context
-//! of both parent and child template.
+//! blocks from the base template with those from the child template.
+//! Variables used in the base template are looked up in the inheriting
+//! template context `struct`, so the base template does not need a context
+//! `struct` of its own. Alternatively, the variables for the base template
+//! can be kept in a separate field: the inheriting template then derefs to
+//! the field marked with `#[template(parent)]` or, failing that, the field
+//! called `_parent`, so that blocks can refer to the context of both parent
+//! and child template:
+//!
+//! ```rust,ignore
+//! #[derive(Template)]
+//! #[template(path = "child.html")]
+//! struct ChildTemplate<'a> {
+//!     #[template(parent)]
+//!     layout: Layout<'a>,
+//!     items: Vec<Item>,
+//! }
+//! ```
 //!
-//! Note that, if the base template lives in another module than the child
-//! template, the child template's module should import all symbols from the
-//! base template's module in order for it to find the trait definition that
-//! supports the inheritance mechanism.
+//! Base templates can themselves extend other templates, so inheritance
+//! chains can be as long as needed. Inside a block, `{{ super() }}` renders
+//! the definition of the same block from the parent template:
+//!
+//! ```text
+//! {% block head %}
+//!   {{ super() }}
+//!   <script src="/page.js"></script>
+//! {% endblock %}
+//! ```
+//!
+//! The inheritance chain is resolved at compile time: the code generated for
+//! the deriving template includes the base templates' content and the most
+//! derived definition of each block, so rendering involves no dynamic
+//! dispatch, and blocks of base templates can use the variables of the
+//! deriving template. Earlier versions generated a `TraitFrom...` trait for
+//! each base template, implemented by its context `struct`, and rendered the
+//! base template through the `_parent` field. These traits are no longer
+//! generated, so base templates do not need a context `struct` of their own
+//! and child templates no longer need to import the base template's module;
+//! code that referred to the generated traits by name has to be changed.
+//!
+//! ### Rendering a single block
+//!
+//! Any block of a template, including those defined by base templates, can
+//! also be rendered on its own, which is useful to update parts of a page.
+//! For each block, a `render_block_<name>()` method (and a corresponding
+//! `render_block_<name>_into()` method) is generated on the template context
+//! `struct`. Blocks can also be selected by name at run time through
+//! `Template::render_block()`, which returns an `ErrorKind::BlockNotFound`
+//! error for unknown block names:
+//!
+//! ```rust,ignore
+//! let comments = page.render_block_comments()?;
+//! let comments = page.render_block("comments")?;
+//! ```
 //!
 //! ## HTML escaping
 //!
@@ -176,15 +272,108 @@
 //! or `xml`. When specifying a template as `source` in an attribute, the
 //! `ext` attribute parameter must be used to specify a type. Additionally,
 //! you can specify an escape mode explicitly for your template by setting
-//! the `escape` attribute parameter value (to `none` or `html`).
+//! the `escape` attribute parameter value.
 //!
-//! Askama escapes `<`, `>`, `&`, `"`, `'`, `\` and `/`, according to the
-//! [OWASP escaping recommendations][owasp]. Use the `safe` filter to
+//! In HTML, Askama escapes `<`, `>`, `&`, `"`, `'`, `\` and `/`, according
+//! to the [OWASP escaping recommendations][owasp]. Use the `safe` filter to
 //! prevent escaping for a single expression, or the `escape` (or `e`)
-//! filter to escape a single expression in an unescaped context.
+//! filter to escape a single expression in an unescaped context (where it
+//! escapes HTML).
+//!
+//! Values of types that cannot contain markup, which are numbers and `bool`,
+//! are written without escaping in HTML text and plain attribute values.
+//! Other types can opt into this by implementing the `HtmlSafe` marker
+//! trait, if their `Display` output never contains characters that would be
+//! escaped:
+//!
+//! ```rust
+//! struct Fraction(u8, u8);
+//!
+//! impl std::fmt::Display for Fraction {
+//!     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+//!         write!(f, "{}/{}", self.0, self.1)
+//!     }
+//! }
+//!
+//! impl askama::HtmlSafe for Fraction {}
+//! ```
+//!
+//! Values that are HTML markup already are not escaped either. Templates
+//! that escape HTML implement the `Markup` trait, so that one template can
+//! be embedded in another with `{{ inner }}` without escaping its output
+//! twice. Other types can implement `Markup` if their output is known to
+//! be safe HTML, and single values can be wrapped in `PreEscaped`:
+//!
+//! ```rust
+//! let sanitized = askama::PreEscaped("<p>From the CMS</p>".to_owned());
+//! # let _ = sanitized;
+//! ```
+//!
+//! Like `HtmlSafe` values, markup is only written as it is in HTML text and
+//! plain attribute values. Elsewhere, for example in a `<script>` element or
+//! an `href` attribute, it is escaped for that context like any other value.
 //!
 //! [owasp]: https://www.owasp.org/index.php/XSS_(Cross_Site_Scripting)_Prevention_Cheat_Sheet#RULE_.231_-_HTML_Escape_Before_Inserting_Untrusted_Data_into_HTML_Element_Content
 //!
+//! The escaping is aware of the HTML around each expression. Askama follows
+//! the literal markup of the template to pick an escaper for the context:
+//!
+//! * in text, comments and quoted attribute values, HTML is escaped;
+//! * in URL attributes like `href` and `src`, a complete URL is checked to
+//!   have a safe scheme (`http`, `https` or `mailto`, otherwise it is
+//!   replaced by `#`) and characters not allowed in URLs are percent-encoded;
+//!   after a `?` or `#`, values are encoded as URL components;
+//! * in JavaScript string literals, in `<script>` elements or event handler
+//!   attributes such as `onclick`, JavaScript is escaped;
+//! * in `<style>` elements and `style` attributes, CSS is escaped.
+//!
+//! Expressions in places that cannot be made safe by escaping, like tag
+//! names, unquoted attribute values or JavaScript code outside of string
+//! literals, are rejected at compile time unless they are marked as `safe`.
+//! The `escape` filter and the `~` operator escape their values for the
+//! context of the expression in the same way, and are rejected in the same
+//! places.
+//! For the same reason, all branches of `if` and `match` tags, and the body
+//! of a `for` loop, must end in the same context they started in, though
+//! attributes can be written conditionally.
+//!
+//! Other output formats are supported by the escapers in the
+//! [`escaping` module](escaping/index.html): `xml`, `js` (for JavaScript
+//! string literals), `css` (for CSS strings and identifiers), `url` (for
+//! URL components), `latex` and `shell` (quoting a POSIX shell word).
+//! To use an escaper of your own, implement the `Escaper` trait for a unit
+//! `struct` and pass its path as the escape mode:
+//!
+//! ```rust,ignore
+//! struct Shout;
+//!
+//! impl askama::Escaper for Shout {
+//!     fn write_escaped<W: std::fmt::Write>(&self, mut fmt: W, s: &str) -> std::fmt::Result {
+//!         fmt.write_str(&s.to_uppercase())
+//!     }
+//! }
+//!
+//! #[derive(Template)]
+//! #[template(path = "hello.txt", escape = "Shout")]
+//! struct HelloTemplate<'a> {
+//!     name: &'a str,
+//! }
+//! ```
+//!
+//! The escape mode can be changed for a part of a template with an
+//! `autoescape` block, which takes the name of an escape mode (or the path
+//! to an escaper), or `true` and `false` for HTML and no escaping. The mode
+//! also applies to macros called and templates included inside the block:
+//!
+//! ```text
+//! {% autoescape false %}
+//!   {{ preformatted }}
+//! {% endautoescape %}
+//! {% autoescape "js" %}
+//!   {% include "config.js" %}
+//! {% endautoescape %}
+//! ```
+//!
 //! ## Control structures
 //!
 //! ### For
@@ -219,6 +408,32 @@
 //! {% endif %}
 //! ```
 //!
+//! Conditions can also match a pattern with `if let`, which binds the
+//! names from the pattern for use in that branch. Patterns support the
+//! same variants and parameters as `when` blocks in a `match`:
+//!
+//! ```text
+//! {% if let Some(user) = session.user %}
+//!   Welcome back, {{ user.name }}
+//! {% else if let Some(0) = unread %}
+//!   No new messages
+//! {% else %}
+//!   Please log in
+//! {% endif %}
+//! ```
+//!
+//! A `let` can also take a pattern followed by `else`. If the pattern does
+//! not match, the block up to `endlet` is rendered instead of the rest of
+//! the enclosing block (or template); otherwise the bindings are available
+//! for the rest of it:
+//!
+//! ```text
+//! {% let Some(user) = session.user else %}
+//!   Please log in
+//! {% endlet %}
+//! Welcome back, {{ user.name }}
+//! ```
+//!
 //! ### Match
 //!
 //! In order to deal with Rust `enum`s in a type-safe way, templates support
@@ -263,16 +478,119 @@
 //! template path. Use `include` within the branches of an `if`/`else`
 //! block to use includes more dynamically.
 //!
+//! Values can also be passed to the included template explicitly. The
+//! included template then gets its own scope, in which only the passed
+//! values (and the fields of the template context) are available, so that
+//! it does not depend on the names used by the including template:
+//!
+//! ```text
+//! {% for entry in entries %}
+//!   {% include "row.html" with item = entry, striped = true %}
+//! {% endfor %}
+//! ```
+//!
+//! Including a template that does not exist is an error, unless the include
+//! is marked with `ignore missing`, in which case it renders nothing:
+//!
+//! ```text
+//! {% include "optional.html" ignore missing %}
+//! ```
+//!
+//! For generating indentation-sensitive output like YAML or source code, the
+//! lines of the included template (except the first one, which starts at the
+//! include site) can be indented by a number of spaces:
+//!
+//! ```text
+//! fields:
+//!   {% include "fields.yaml" indent 2 %}
+//! ```
+//!
+//! The `indent(n)` filter does the same for values; `indent(n, true)` also
+//! indents the first line. Its arguments are positional only. When
+//! combined, the include options are given in the order `ignore missing`,
+//! `indent`, `with`.
+//!
+//! ### Component
+//!
+//! A *component* renders another template context `struct` inline, which
+//! gives reusable parts of a page typed arguments. The fields are given
+//! like in a Rust `struct` expression and are passed by value; write `&`
+//! to pass a reference instead. The body of the component is rendered
+//! first and passed as the `slot` field, wrapped in `PreEscaped` so that
+//! it is not escaped again:
+//!
+//! ```text
+//! {% component Card { title: &post.title } %}
+//!   <p>{{ post.body }}</p>
+//! {% endcomponent %}
+//! ```
+//!
+//! ```rust,ignore
+//! #[derive(Template)]
+//! #[template(source = "<h2>{{ title }}</h2>{{ slot }}", ext = "html")]
+//! struct Card<'a> {
+//!     title: &'a str,
+//!     slot: askama::PreEscaped<String>,
+//! }
+//! ```
+//!
+//! The component is rendered directly into the output with its own
+//! `Template::render_into()` implementation. Components without a body
+//! get no `slot` field.
+//!
+//! ### Content stacks
+//!
+//! Templates can add content to a named stack from anywhere in the render
+//! tree, including child templates, includes and components, with `push`.
+//! A layout then writes out all content pushed to a stack with `stack`,
+//! even if it was pushed later on:
+//!
+//! ```text
+//! <head>{% stack "scripts" %}</head>
+//! ```
+//!
+//! ```text
+//! {% push "scripts" %}<script src="map.js"></script>{% endpush %}
+//! ```
+//!
+//! Content wrapped in `once` is only written the first time the same content
+//! is rendered, which is useful for includes used several times:
+//!
+//! ```text
+//! {% push "scripts" %}{% once %}<script src="map.js"></script>{% endonce %}{% endpush %}
+//! ```
+//!
+//! A `stack` cannot be used inside `push`, `once` or component bodies.
+//!
 //! ## Expressions
 //!
-//! Askama supports string literals (`"foo"`) and integer literals (`1`).
+//! Askama supports string literals (`"foo"`), integer literals (`1`) and
+//! `true` and `false`.
 //! It supports almost all binary operators that Rust supports,
-//! including arithmetic, comparison and logic operators.
+//! including arithmetic, comparison and logic operators, and the unary
+//! operators `!`, `-` and `&`.
 //! The parser applies the same precedence order as the Rust compiler.
 //! Expressions can be grouped using parentheses.
+//! The `~` operator concatenates the `Display` output of its operands into
+//! a `String`, as in `{{ "item-" ~ id }}`. If one of the operands is marked
+//! safe, the other operands are escaped individually, so the result is
+//! still correctly escaped as a whole.
 //! The HTML special characters `&`, `<` and `>` will be replaced with their
 //! character entities unless the `escape` mode is disabled for a template.
 //! Methods can be called on variables that are in scope, including `self`.
+//! A method call (or any other expression) yielding a `Result` can be
+//! followed by the `?` operator, as in `{{ self.avatar_url()? }}`. The
+//! `Err` value is then returned from `render_into()`, boxed into an
+//! `askama::Error` with the `ErrorKind::Custom` kind.
+//!
+//! Fields and methods of `Option` values can be reached with `&.`, which
+//! yields `None` if any part of the chain is `None`, including a last field
+//! that is an `Option` itself. The `??` operator
+//! renders its right-hand side if the left-hand side is `None`, as in
+//! `{{ user&.profile&.display_name ?? "Anonymous" }}`; it is shorthand
+//! for the `default` filter. A `?.` is not optional chaining: it accesses
+//! a field on the result of the `?` operator, as in
+//! `{{ self.load()?.name }}`.
 //!
 //! **Warning**: if the result of an expression (a `{{ }}` block) is
 //! equivalent to `self`, this can result in a stack overflow from infinite
@@ -283,6 +601,120 @@
 //!
 //! Askama supports block comments delimited by `{#` and `#}`.
 //!
+//! # Configuration
+//!
+//! Settings that apply to all templates in a crate can be given in an
+//! `askama.toml` file next to its `Cargo.toml`. The file is read and
+//! validated once per compilation; any problem with it is reported as a
+//! compile-time error. All settings are optional:
+//!
+//! ```toml
+//! [general]
+//! # Directories to search for templates, in order, relative to the crate root
+//! dirs = ["templates", "../shared/templates"]
+//! # The syntax used by templates without a `syntax` option
+//! default_syntax = "default"
+//! # Whether to "preserve" or "suppress" whitespace around tags by default
+//! whitespace = "preserve"
+//! # The default for the `print` option
+//! print = "none"
+//!
+//! # A custom syntax, used with `#[template(syntax = "brackets", ...)]`;
+//! # delimiters that are left out keep their default value
+//! [[syntax]]
+//! name = "brackets"
+//! block_start = "[%"
+//! block_end = "%]"
+//! expr_start = "[["
+//! expr_end = "]]"
+//! comment_start = "[#"
+//! comment_end = "#]"
+//! # Templates with these extensions use this syntax unless they set another
+//! extensions = ["btxt"]
+//!
+//! # The escape mode for templates with these extensions, which takes
+//! # precedence over the built-in mapping for `html`, `htm` and `xml`
+//! [[escaper]]
+//! path = "latex"
+//! extensions = ["tex"]
+//! ```
+//!
+//! The `path` of an escaper can be any of the built-in escape modes, or the
+//! path to a custom `Escaper` type.
+//!
+//! Each template is parsed with its own syntax: the one given with the
+//! `syntax` option for the deriving template, and otherwise the syntax
+//! configured for the template's extension, or the default syntax. Templates
+//! given with `source` are treated as if they were in the first template
+//! directory from `askama.toml` (or `templates`), for finding the templates
+//! they refer to.
+//!
+//! ## Template directories
+//!
+//! Templates referred to by `path`, `extends`, `include` and `import` are
+//! looked up in each of the template directories in turn, and the first
+//! match is used. Directories listed in the `ASKAMA_TEMPLATE_DIRS_<PKG>`
+//! environment variable are searched before the ones from `askama.toml`,
+//! where `<PKG>` is the name of the package in upper case, with `-`
+//! replaced by `_` (as in `ASKAMA_TEMPLATE_DIRS_MY_SITE` for `my-site`).
+//! The directories are separated like the entries of `PATH` and relative to
+//! the crate root; those that do not exist are skipped. Changing the
+//! variable rebuilds the templates of the package.
+//!
+//! A path used in another template is first taken to be relative to that
+//! template, and then relative to the template directories. Since the
+//! relative path is also looked up in every template directory, a template
+//! in an earlier directory overrides one with the same name in a later
+//! directory, even if it is used by a template from the later directory.
+//! This makes it possible to customize a shared set of templates by only
+//! replacing some of them:
+//!
+//! ```toml
+//! [general]
+//! dirs = ["templates", "../theme/templates"]
+//! ```
+//!
+//! If a template cannot be found, the error lists all locations that were
+//! tried.
+//!
+//! Templates generated by a build script can be used by adding a directory
+//! in `OUT_DIR` to the template directories, as in `"$OUT_DIR/templates"`,
+//! or with the `in_out_dir` option of the `template()` attribute. The
+//! `rerun_if_templates_changed()` helper does not watch directories in
+//! `OUT_DIR`, since their contents are written by the build script. Crates
+//! without a build script have no `OUT_DIR`; directories in it are skipped
+//! for them.
+//!
+//! ## Templates from other crates
+//!
+//! A crate can make its templates available to other crates, for example to
+//! share base templates and macros. The exporting crate needs a `links` key
+//! in the `[package]` section of its `Cargo.toml`, and a build script that
+//! calls `askama::export_templates()`:
+//!
+//! ```toml
+//! [package]
+//! name = "company-ui"
+//! links = "company-ui-templates"
+//! build = "build.rs"
+//! ```
+//!
+//! Crates that depend on it directly can then import its templates by
+//! calling `askama::import_templates()` from their build script, and refer
+//! to them by prefixing the path with `@` and the crate name, with dashes
+//! replaced by underscores:
+//!
+//! ```text
+//! {% extends "@company_ui/base.html" %}
+//! {% import "@company_ui/macros.html" as ui %}
+//! ```
+//!
+//! Paths used in the templates of another crate are looked up in that crate
+//! only. Since a deriving template is compiled along with all templates it
+//! uses, no code needs to be exported for this; the templates of the other
+//! crate are parsed with the syntaxes configured by the crate using them. A
+//! crate can only use templates of crates it depends on directly.
+//!
 //! # Optional functionality
 //!
 //! ## Rocket integration
@@ -320,8 +752,9 @@
 extern crate askama_derive;
 extern crate askama_shared as shared;
 
-use shared::path;
+use shared::config::{self, Config};
 
+use std::env;
 use std::fs::{self, DirEntry};
 use std::io;
 use std::path::Path;
@@ -336,11 +769,35 @@ pub trait Template {
         self.render_into(&mut buf)?;
         Ok(buf)
     }
+    /// Renders the template to the given `writer` buffer, adding the content
+    /// pushed by it to the given stacks; used to render components
+    #[doc(hidden)]
+    fn render_into_stacks(&self, writer: &mut std::fmt::Write, _stacks: &Stacks) -> Result<()> {
+        self.render_into(writer)
+    }
+    /// Renders only the named block of the template to the given `writer`
+    /// buffer, returning an `ErrorKind::BlockNotFound` error if the template
+    /// does not define a block with that name
+    fn render_block_into(&self, block: &str, _writer: &mut std::fmt::Write) -> Result<()> {
+        Err(ErrorKind::BlockNotFound(block.to_owned()).into())
+    }
+    /// Helper method which allocates a new `String` and renders the named
+    /// block into it
+    fn render_block(&self, block: &str) -> Result<String> {
+        let mut buf = String::new();
+        self.render_block_into(block, &mut buf)?;
+        Ok(buf)
+    }
 }
 
 pub use shared::filters;
 pub use askama_derive::*;
-pub use shared::{Error, MarkupDisplay, Result};
+pub use shared::{Error, ErrorKind, Escaper, HtmlSafe, IndentWriter, Markup, MarkupDisplay};
+pub use shared::{PreEscaped, Result};
+pub use shared::escaping;
+#[doc(hidden)]
+pub use shared::chain;
+pub use shared::{StackBuffer, StackWriter, Stacks};
 
 #[cfg(feature = "with-iron")]
 pub mod iron {
@@ -388,15 +845,63 @@ fn visit_dirs(dir: &Path, cb: &Fn(&DirEntry)) -> io::Result<()> {
 
 /// Build script helper to rebuild crates if contained templates have changed
 ///
-/// Iterates over all files in the template dir (`templates` in
-/// `CARGO_MANIFEST_DIR`) and writes a `cargo:rerun-if-changed=` line for each
-/// of them to stdout.
+/// Iterates over all files in the template dirs (`templates` in
+/// `CARGO_MANIFEST_DIR`, unless configured otherwise in `askama.toml`) and
+/// writes a `cargo:rerun-if-changed=` line for each of them to stdout, as
+/// well as for `askama.toml` itself.
 ///
 /// This helper method can be used in build scripts (`build.rs`) in crates
 /// that have templates, to make sure the crate gets rebuilt when template
-/// source code changes.
+/// source code or configuration changes.
 pub fn rerun_if_templates_changed() {
-    visit_dirs(&path::template_dir(), &|e: &DirEntry| {
-        println!("cargo:rerun-if-changed={}", e.path().to_str().unwrap());
-    }).unwrap();
+    let config = Config::new(&config::read_config_file());
+    let out_dir = env::var_os("OUT_DIR");
+    for dir in &config.dirs {
+        // Templates generated by the build script itself are not watched
+        if out_dir.as_ref().map_or(false, |out_dir| dir.starts_with(out_dir)) {
+            continue;
+        }
+        visit_dirs(dir, &|e: &DirEntry| {
+            println!("cargo:rerun-if-changed={}", e.path().to_str().unwrap());
+        }).unwrap();
+    }
+    println!("cargo:rerun-if-changed={}", config::config_file_path().to_str().unwrap());
+    println!("cargo:rerun-if-env-changed={}", config::template_dirs_var());
+}
+
+/// Build script helper to make a crate's templates available to other crates
+///
+/// Passes the template dirs of the crate on to the build scripts of crates
+/// depending on it, which can make them available to their templates with
+/// `import_templates()`. Cargo only does this for crates with a `links` key
+/// in the `[package]` section of their `Cargo.toml`. Also calls
+/// `rerun_if_templates_changed()`.
+pub fn export_templates() {
+    let config = Config::new(&config::read_config_file());
+    let name = env::var("CARGO_PKG_NAME").unwrap().replace('-', "_");
+    let dirs = env::join_paths(&config.dirs).unwrap();
+    println!("cargo:askama_crate={}", name);
+    println!("cargo:askama_dirs={}", dirs.to_str().unwrap());
+    rerun_if_templates_changed();
 }
+
+/// Build script helper to use templates exported by dependencies
+///
+/// Makes the templates of all direct dependencies that call
+/// `export_templates()` in their build script available to the templates of
+/// this crate, as `@name/path`, where `name` is the name of the dependency
+/// with dashes replaced by underscores.
+pub fn import_templates() {
+    for (key, name) in env::vars() {
+        if !key.starts_with("DEP_") || !key.ends_with(EXPORT_CRATE_SUFFIX) {
+            continue;
+        }
+        let dirs_key = format!("{}_ASKAMA_DIRS", &key[..key.len() - EXPORT_CRATE_SUFFIX.len()]);
+        let dirs = env::var(&dirs_key).unwrap();
+        println!("cargo:rerun-if-env-changed={}", key);
+        println!("cargo:rerun-if-env-changed={}", dirs_key);
+        println!("cargo:rustc-env={}{}={}", config::CRATE_TEMPLATES_VAR_PREFIX, name, dirs);
+    }
+}
+
+const EXPORT_CRATE_SUFFIX: &str = "_ASKAMA_CRATE";
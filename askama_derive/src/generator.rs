@@ -1,124 +1,80 @@
-use input::TemplateInput;
-use parser::{self, Cond, Expr, Macro, MatchParameter, MatchVariant, Node, Target, When, WS};
-use shared::{filters, path};
+use heritage::{self, Context, Contexts, Heritage};
+use html::HtmlContext;
+use input::{EscapeMode, TemplateInput};
+use parser::{Component, Cond, CondTest, Expr, Include, MatchParameter, MatchVariant, Node};
+use parser::{Target, When, WS};
+use shared::{config, filters};
 
 use quote::ToTokens;
 use proc_macro2::Span;
 
-use std::{cmp, hash, str};
-use std::path::Path;
-use std::collections::{HashMap, HashSet};
+use std::{cmp, hash, mem, str};
+use std::collections::HashSet;
 
 use syn;
 
 
-pub fn generate(input: &TemplateInput, nodes: &[Node], imported: &HashMap<(&str, &str), Macro>)
-                -> String {
-    Generator::default().build(&State::new(input, nodes, imported))
+pub fn generate(input: &TemplateInput, contexts: &Contexts) -> String {
+    let ctx = &contexts[&input.path];
+    let heritage = Heritage::new(ctx, contexts);
+    check_required_blocks(input, ctx, &heritage);
+    Generator::default(input, contexts, &heritage).build(ctx)
 }
 
-struct State<'a> {
-    input: &'a TemplateInput<'a>,
-    nodes: &'a [Node<'a>],
-    blocks: Vec<&'a Node<'a>>,
-    macros: MacroMap<'a>,
-    trait_name: String,
-    derived: bool,
-}
-
-impl<'a> State<'a> {
-    fn new<'n>(input: &'n TemplateInput, nodes: &'n [Node], imported:
-               &'n HashMap<(&'n str, &'n str), Macro<'n>>) -> State<'n> {
-        let mut base = None;
-        let mut blocks = Vec::new();
-        let mut macros = HashMap::new();
-
-        for n in nodes {
-            match n {
-                Node::Extends(Expr::StrLit(path)) => match base {
-                    Some(_) => panic!("multiple extend blocks found"),
-                    None => {
-                        base = Some(*path);
-                    },
-                },
-                def @ Node::BlockDef(_, _, _, _) => {
-                    blocks.push(def);
-                },
-                Node::Macro(name, m) => {
-                    macros.insert((None, *name), m);
-                },
-                _ => {},
+// Makes sure every block marked as `required` by a base template is overridden
+// by the deriving template or one of the templates in between.
+fn check_required_blocks(input: &TemplateInput, ctx: &Context, heritage: &Heritage) {
+    let mut names = heritage.blocks.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        let (def_ctx, def) = heritage.blocks[name][0];
+        if let Node::BlockDef(_, _, true, _, _) = *def {
+            if def_ctx.path != ctx.path {
+                panic!("template '{}' does not override required block '{}' of '{}'",
+                       input.ast.ident, name, def_ctx.path.display());
             }
         }
-
-        let mut check_nested = 0;
-        let mut nested_blocks = Vec::new();
-        while check_nested < blocks.len() {
-            if let Node::BlockDef(_, _, ref nodes, _) = blocks[check_nested] {
-                for n in nodes {
-                    if let def @ Node::BlockDef(_, _, _, _) = n {
-                        nested_blocks.push(def);
-                    }
-                }
-            } else {
-                panic!("non block found in list of blocks");
-            }
-            blocks.append(&mut nested_blocks);
-            check_nested += 1;
-        }
-
-        for (&(scope, name), m) in imported {
-            macros.insert((Some(scope), name), m);
-        }
-
-        State {
-            input,
-            nodes,
-            blocks,
-            macros,
-            trait_name: match base {
-                Some(user_path) => trait_name_for_path(
-                    &path::find_template_from_path(user_path, Some(&input.path))
-                ),
-                None => trait_name_for_path(&input.path),
-            },
-            derived: base.is_some(),
-        }
-    }
-}
-
-fn trait_name_for_path(path: &Path) -> String {
-    let mut res = String::new();
-    res.push_str("TraitFrom");
-    for c in path.to_string_lossy().chars() {
-        if c.is_alphanumeric() {
-            res.push(c);
-        } else {
-            res.push_str(&format!("{:x}", c as u32));
-        }
     }
-    res
 }
 
-fn get_parent_type(ast: &syn::DeriveInput) -> Option<&syn::Type> {
-    match ast.data {
+// Finds the field holding the parent template context, which is either marked
+// with `#[template(parent)]` or named `_parent`.
+fn get_parent_field(ast: &syn::DeriveInput) -> Option<&syn::Field> {
+    let fields = match ast.data {
         syn::Data::Struct(syn::DataStruct {
             fields: syn::Fields::Named(ref fields),
             ..
-        }) => fields.named.iter().filter_map(|f| {
-            f.ident.as_ref().and_then(|name| {
-                if name == "_parent" {
-                    Some(&f.ty)
-                } else {
-                    None
-                }
-            })
-        }),
+        }) => &fields.named,
+        syn::Data::Struct(_) => return None,
         _ => panic!("derive(Template) only works for struct items"),
-    }.next()
+    };
+
+    let mut marked = fields.iter().filter(|f| f.attrs.iter().any(is_parent_attr));
+    match (marked.next(), marked.next()) {
+        (Some(_), Some(_)) => panic!("only one field can be marked with #[template(parent)]"),
+        (Some(f), None) => Some(f),
+        (None, _) => fields.iter().find(|f| {
+            f.ident.as_ref().map_or(false, |name| name == "_parent")
+        }),
+    }
+}
+
+fn is_parent_attr(attr: &syn::Attribute) -> bool {
+    match attr.interpret_meta() {
+        Some(syn::Meta::List(ref list)) if list.ident == "template" => {
+            list.nested.iter().any(|nm| match *nm {
+                syn::NestedMeta::Meta(syn::Meta::Word(ref ident)) if ident == "parent" => true,
+                _ => panic!("unsupported annotation on template field, expected 'parent'"),
+            })
+        },
+        _ => false,
+    }
 }
 
 struct Generator<'a> {
+    input: &'a TemplateInput<'a>,
+    contexts: &'a Contexts<'a>,
+    heritage: &'a Heritage<'a>,
     buf: String,
     indent: u8,
     start: bool,
@@ -126,12 +82,24 @@ struct Generator<'a> {
     next_ws: Option<&'a str>,
     skip_ws: bool,
     vars: usize,
-    impl_blocks: bool,
+    super_block: Option<(&'a str, usize)>,
+    // Whether the output currently goes into a fragment rather than the
+    // template output, as in `push` bodies
+    in_fragment: bool,
+    // The HTML context of the output so far, if the template escapes HTML
+    html: Option<HtmlContext>,
+    // The escape mode in effect, as changed by `autoescape` tags
+    escaping: EscapeMode,
 }
 
 impl<'a> Generator<'a> {
-    fn new<'n>(locals: SetChain<'n, &'n str>, indent: u8) -> Generator<'n> {
+    fn new<'n>(input: &'n TemplateInput<'n>, contexts: &'n Contexts<'n>,
+               heritage: &'n Heritage<'n>, locals: SetChain<'n, &'n str>, indent: u8)
+               -> Generator<'n> {
         Generator {
+            input,
+            contexts,
+            heritage,
             buf: String::new(),
             indent,
             start: true,
@@ -139,142 +107,201 @@ impl<'a> Generator<'a> {
             next_ws: None,
             skip_ws: false,
             vars: 0,
-            impl_blocks: false,
+            super_block: None,
+            in_fragment: false,
+            html: None,
+            escaping: input.meta.escaping.clone(),
         }
     }
 
-    fn default<'n>() -> Generator<'n> {
-        Self::new(SetChain::new(), 0)
+    fn default<'n>(input: &'n TemplateInput<'n>, contexts: &'n Contexts<'n>,
+                   heritage: &'n Heritage<'n>) -> Generator<'n> {
+        let mut gen = Self::new(input, contexts, heritage, SetChain::new(), 0);
+        if input.meta.escaping == EscapeMode::Html {
+            gen.html = Some(HtmlContext::new());
+        }
+        gen
     }
 
     fn child(&mut self) -> Generator {
         let locals = SetChain::with_parent(&self.locals);
-        Self::new(locals, self.indent)
+        let mut child = Self::new(self.input, self.contexts, self.heritage, locals, self.indent);
+        child.super_block = self.super_block;
+        child.in_fragment = self.in_fragment;
+        child.html = self.html.clone();
+        child.escaping = self.escaping.clone();
+        child
     }
 
-    // Takes a State and generates the relevant implementations.
-    fn build(mut self, state: &'a State) -> String {
-        if !state.blocks.is_empty() {
-            if !state.derived {
-                self.define_trait(state);
-            } else {
-                let parent_type = get_parent_type(state.input.ast)
-                    .expect("expected field '_parent' in extending template struct");
-                self.deref_to_parent(state, parent_type);
+    // Takes a Context and generates the relevant implementations.
+    fn build(mut self, ctx: &'a Context) -> String {
+        if ctx.extends.is_some() {
+            if let Some(parent) = get_parent_field(self.input.ast) {
+                self.deref_to_parent(parent);
             }
+        }
 
-            let trait_nodes = if !state.derived {
-                Some(&state.nodes[..])
-            } else {
-                None
-            };
-            self.impl_trait(state, trait_nodes);
-            self.impl_template_for_trait(state);
-        } else {
-            self.impl_template(state);
+        self.impl_blocks();
+        self.impl_template();
+        self.impl_display();
+        if self.input.meta.escaping == EscapeMode::Html {
+            self.impl_markup();
         }
-        self.impl_display(state);
         if cfg!(feature = "iron") {
-            self.impl_modifier_response(state);
+            self.impl_modifier_response();
         }
         if cfg!(feature = "rocket") {
-            self.impl_responder(state);
+            self.impl_responder();
         }
         self.buf
     }
 
     // Implement `Template` for the given context struct.
-    fn impl_template(&mut self, state: &'a State) {
-        self.write_header(state, "::askama::Template", None);
+    fn impl_template(&mut self) {
+        self.write_header(Some("::askama::Template"), None);
         self.writeln("fn render_into(&self, writer: &mut ::std::fmt::Write) -> \
                       ::askama::Result<()> {");
-        self.writeln("#[allow(unused_imports)] use ::std::ops::Deref as HiddenDerefTrait;");
-        self.handle(state, state.nodes, AstLevel::Top);
-        self.flush_ws(&WS(false, false));
-        self.writeln("Ok(())");
-        self.writeln("}");
+        self.writeln("self.render_into_stacks(writer, &::askama::Stacks::new())");
         self.writeln("}");
-    }
 
-    // Implement `Display` for the given context struct.
-    fn impl_display(&mut self, state: &'a State) {
-        self.write_header(state, "::std::fmt::Display", None);
-        self.writeln("fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {");
-        self.writeln("self.render_into(f).map_err(|_| ::std::fmt::Error {})");
+        self.writeln("#[allow(unused_variables)]");
+        self.writeln("fn render_into_stacks(&self, writer: &mut ::std::fmt::Write, \
+                      askama_stacks: &::askama::Stacks) -> ::askama::Result<()> {");
+        self.write_imports();
+        // Lets the compiler track the variable, so that changing the template
+        // directories rebuilds the template
+        self.writeln(&format!("let _ = option_env!({:?});", config::template_dirs_var()));
+        let root = self.heritage.root;
+        self.reset_html();
+        self.write_stack_buffered(|gen| gen.handle(root, root.nodes, AstLevel::Top));
+        self.writeln("Ok(())");
         self.writeln("}");
+
+        let names = self.block_names();
+        if !names.is_empty() {
+            self.writeln("fn render_block_into(&self, block: &str, writer: &mut ::std::fmt::Write) \
+                          -> ::askama::Result<()> {");
+            self.writeln("match block {");
+            for name in names {
+                self.writeln(&format!(
+                    "{:?} => self.render_block_{}_into(writer),",
+                    name, name.trim_left_matches("r#")
+                ));
+            }
+            self.writeln("_ => Err(::askama::ErrorKind::BlockNotFound(block.to_owned()).into()),");
+            self.writeln("}");
+            self.writeln("}");
+        }
         self.writeln("}");
     }
 
-    // Implement `Deref<Parent>` for an inheriting context struct.
-    fn deref_to_parent(&mut self, state: &'a State, parent_type: &syn::Type) {
-        self.write_header(state, "::std::ops::Deref", None);
-        self.writeln(&format!("type Target = {};", parent_type.into_token_stream()));
-        self.writeln("fn deref(&self) -> &Self::Target {");
-        self.writeln("&self._parent");
-        self.writeln("}");
+    // Implement methods to render each block on its own for the given
+    // context struct.
+    fn impl_blocks(&mut self) {
+        let names = self.block_names();
+        if names.is_empty() {
+            return;
+        }
+
+        self.write_header(None, None);
+        for name in names {
+            let name_ident = name.trim_left_matches("r#");
+            self.writeln("#[allow(dead_code, unused_variables)]");
+            self.writeln(&format!(
+                "pub fn render_block_{}_into(&self, writer: &mut ::std::fmt::Write) \
+                 -> ::askama::Result<()> {{",
+                name_ident
+            ));
+            self.write_imports();
+            self.writeln("let askama_stacks = &::askama::Stacks::new();");
+            self.reset_html();
+            self.write_stack_buffered(|gen| gen.write_block(Some(name), &WS(false, false)));
+            self.writeln("Ok(())");
+            self.writeln("}");
+
+            self.writeln("#[allow(dead_code)]");
+            self.writeln(&format!(
+                "pub fn render_block_{}(&self) -> ::askama::Result<String> {{",
+                name_ident
+            ));
+            self.writeln("let mut buf = String::new();");
+            self.writeln(&format!("self.render_block_{}_into(&mut buf)?;", name_ident));
+            self.writeln("Ok(buf)");
+            self.writeln("}");
+        }
         self.writeln("}");
     }
 
-    // Implement `TraitFromPathName` for the given context struct.
-    fn impl_trait(&mut self, state: &'a State, nodes: Option<&'a [Node]>) {
-        self.write_header(state, &state.trait_name, None);
-        self.write_block_defs(state);
+    // Writes the code generated by `body`. If any of the templates in use has
+    // a `stack` tag, the output is buffered, so that fragments pushed after
+    // the stack tag can be filled in before writing it out.
+    fn write_stack_buffered<F>(&mut self, body: F) where F: FnOnce(&mut Self) {
+        let buffered = self.contexts.values().any(|ctx| has_stack(ctx.nodes));
+        if buffered {
+            self.writeln("let askama_buf = ::askama::StackBuffer::new();");
+            self.writeln("let askama_out = writer;");
+            self.writeln("let writer: &mut ::std::fmt::Write = &mut askama_buf.writer();");
+        }
+        body(self);
+        self.flush_ws(&WS(false, false));
+        if buffered {
+            self.writeln("askama_buf.write_into(askama_out, askama_stacks)?;");
+        }
+    }
 
-        self.writeln("#[allow(unused_variables)]");
-        self.writeln(&format!(
-            "fn render_trait_into(&self, timpl: &{}, writer: &mut ::std::fmt::Write) \
-             -> ::askama::Result<()> {{",
-            state.trait_name
-        ));
+    // Brings the traits into scope that the generated code calls methods of.
+    fn write_imports(&mut self) {
         self.writeln("#[allow(unused_imports)] use ::std::ops::Deref as HiddenDerefTrait;");
+        self.writeln("#[allow(unused_imports)] use ::askama::escaping::{\
+                      SelectEscaped as HiddenSelectEscapedTrait, \
+                      SelectMarkup as HiddenSelectMarkupTrait, \
+                      SelectSafe as HiddenSelectSafeTrait};");
+        self.writeln("#[allow(unused_imports)] use ::askama::chain::{\
+                      LiftOption as HiddenLiftOptionTrait, \
+                      LiftValue as HiddenLiftValueTrait};");
+    }
 
-        if let Some(nodes) = nodes {
-            self.impl_blocks = true;
-            self.handle(state, nodes, AstLevel::Top);
-            self.flush_ws(&WS(false, false));
-            self.impl_blocks = false;
-            self.writeln("Ok(())");
-        } else {
-            self.writeln("self._parent.render_trait_into(self, writer)");
-        }
+    // Names of all blocks defined in the inheritance chain, in sorted order.
+    fn block_names(&self) -> Vec<&'a str> {
+        let mut names = self.heritage.blocks.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
 
+    // Implement `Display` for the given context struct.
+    fn impl_display(&mut self) {
+        self.write_header(Some("::std::fmt::Display"), None);
+        self.writeln("fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {");
+        self.writeln("self.render_into(f).map_err(|_| ::std::fmt::Error {})");
         self.writeln("}");
-        self.flush_ws(&WS(false, false));
         self.writeln("}");
     }
 
-    // Implement `Template` for templates that implement a template trait.
-    fn impl_template_for_trait(&mut self, state: &'a State) {
-        self.write_header(state, "::askama::Template", None);
-        self.writeln("fn render_into(&self, writer: &mut ::std::fmt::Write) \
-                      -> ::askama::Result<()> {");
-        if state.derived {
-            self.writeln("self._parent.render_trait_into(self, writer)");
-        } else {
-            self.writeln("self.render_trait_into(self, writer)");
-        }
-        self.writeln("}");
+    // Implement `Markup` for the given context struct, so that it is not
+    // escaped when embedded in other HTML templates.
+    fn impl_markup(&mut self) {
+        self.write_header(Some("::askama::Markup"), None);
         self.writeln("}");
     }
 
-    // Defines the `TraitFromPathName` trait.
-    fn define_trait(&mut self, state: &'a State) {
-        self.writeln(&format!("pub trait {} {{", state.trait_name));
-        self.write_block_defs(state);
-        self.writeln(&format!(
-            "fn render_trait_into(&self, timpl: &{}, writer: &mut ::std::fmt::Write) \
-             -> ::askama::Result<()>;",
-            state.trait_name));
+    // Implement `Deref<Parent>` for an inheriting context struct.
+    fn deref_to_parent(&mut self, parent: &syn::Field) {
+        self.write_header(Some("::std::ops::Deref"), None);
+        let parent_type = &parent.ty;
+        self.writeln(&format!("type Target = {};", parent_type.into_token_stream()));
+        self.writeln("fn deref(&self) -> &Self::Target {");
+        self.writeln(&format!("&self.{}", parent.ident.as_ref().unwrap()));
+        self.writeln("}");
         self.writeln("}");
     }
 
     // Implement iron's Modifier<Response> if enabled
-    fn impl_modifier_response(&mut self, state: &'a State) {
-        self.write_header(state, "::askama::iron::Modifier<::askama::iron::Response>", None);
+    fn impl_modifier_response(&mut self) {
+        self.write_header(Some("::askama::iron::Modifier<::askama::iron::Response>"), None);
         self.writeln("fn modify(self, res: &mut ::askama::iron::Response) {");
         self.writeln("res.body = Some(Box::new(self.render().unwrap().into_bytes()));");
 
-        let ext = state.input.path.extension().map_or("", |s| s.to_str().unwrap_or(""));
+        let ext = self.input.path.extension().map_or("", |s| s.to_str().unwrap_or(""));
         match ext {
             "html" | "htm" => {
                 self.writeln("::askama::iron::ContentType::html().0.modify(res);");
@@ -287,14 +314,14 @@ impl<'a> Generator<'a> {
     }
 
     // Implement Rocket's `Responder`.
-    fn impl_responder(&mut self, state: &'a State) {
+    fn impl_responder(&mut self) {
         let lifetime = syn::Lifetime::new("'askama", Span::call_site());
         let param = syn::GenericParam::Lifetime(syn::LifetimeDef::new(lifetime));
-        self.write_header(state, "::askama::rocket::Responder<'askama>", Some(vec![param]));
+        self.write_header(Some("::askama::rocket::Responder<'askama>"), Some(vec![param]));
         self.writeln("fn respond_to(self, _: &::askama::rocket::Request) \
                       -> ::askama::rocket::Result<'askama> {");
 
-        let ext = match state.input.path.extension() {
+        let ext = match self.input.path.extension() {
             Some(s) => s.to_str().unwrap(),
             None => "txt",
         };
@@ -304,24 +331,23 @@ impl<'a> Generator<'a> {
         self.writeln("}");
     }
 
-    // Writes header for the `impl` for `TraitFromPathName` or `Template`
-    // for the given context struct.
-    fn write_header(&mut self, state: &'a State, target: &str,
-                    params: Option<Vec<syn::GenericParam>>) {
-        let mut generics = state.input.ast.generics.clone();
+    // Writes header for the `impl` for the given trait for the given
+    // context struct, or for an inherent `impl` if no trait is given.
+    fn write_header(&mut self, target: Option<&str>, params: Option<Vec<syn::GenericParam>>) {
+        let mut generics = self.input.ast.generics.clone();
         if let Some(params) = params {
             for param in params {
                 generics.params.push(param);
             }
         }
-        let (_, orig_ty_generics, _) = state.input.ast.generics.split_for_impl();
+        let (_, orig_ty_generics, _) = self.input.ast.generics.split_for_impl();
         let (impl_generics, _, where_clause) = generics.split_for_impl();
         self.writeln(
             format!(
-                "{} {} for {}{} {{",
+                "{} {}{}{} {{",
                 quote!(impl#impl_generics),
-                target,
-                state.input.ast.ident,
+                target.map_or(String::new(), |t| format!("{} for ", t)),
+                self.input.ast.ident,
                 quote!(#orig_ty_generics #where_clause),
             ).as_ref(),
         );
@@ -329,8 +355,8 @@ impl<'a> Generator<'a> {
 
     /* Helper methods for handling node types */
 
-    fn handle(&mut self, state: &'a State, nodes: &'a [Node], level: AstLevel) {
-        for n in nodes {
+    fn handle(&mut self, ctx: &'a Context, nodes: &'a [Node], level: AstLevel) {
+        for (i, n) in nodes.iter().enumerate() {
             match *n {
                 Node::Lit(lws, val, rws) => {
                     self.write_lit(lws, val, rws);
@@ -339,35 +365,66 @@ impl<'a> Generator<'a> {
                     self.write_comment(ws);
                 },
                 Node::Expr(ref ws, ref val) => {
-                    self.write_expr(state, ws, val);
+                    self.write_expr(ws, val);
+                },
+                Node::LetDecl(ref ws, mutable, ref var) => {
+                    self.write_let_decl(ws, mutable, var);
                 },
-                Node::LetDecl(ref ws, ref var) => {
-                    self.write_let_decl(ws, var);
+                Node::Let(ref ws, mutable, ref var, ref val) => {
+                    self.write_let(ws, mutable, var, val);
                 },
-                Node::Let(ref ws, ref var, ref val) => {
-                    self.write_let(ws, var, val);
+                Node::Set(ref ws, ref var, ref val) => {
+                    self.write_set(ws, var, val);
+                },
+                Node::LetElse(ref ws1, ref test, ref nodes_else, ref ws2) => {
+                    // The rest of the nodes are only rendered if the pattern
+                    // matches, so they are handled as part of the `let`.
+                    let rest = &nodes[i + 1..];
+                    self.write_let_else(ctx, ws1, test, nodes_else, ws2, rest, level);
+                    return;
+                },
+                Node::With(ref ws1, ref vars, ref nodes, ref ws2) => {
+                    self.write_with(ctx, ws1, vars, nodes, ws2);
                 },
                 Node::Cond(ref conds, ref ws) => {
-                    self.write_cond(state, conds, ws);
+                    self.write_cond(ctx, conds, ws);
                 },
                 Node::Match(ref ws1, ref expr, inter, ref arms, ref ws2) => {
-                    self.write_match(state, ws1, expr, inter, arms, ws2);
+                    self.write_match(ctx, ws1, expr, inter, arms, ws2);
                 },
                 Node::Loop(ref ws1, ref var, ref iter, ref body, ref ws2) => {
-                    self.write_loop(state, ws1, var, iter, body, ws2);
+                    self.write_loop(ctx, ws1, var, iter, body, ws2);
                 },
-                Node::BlockDef(ref ws1, name, _, ref ws2) => {
-                    if let AstLevel::Nested = level {
+                Node::BlockDef(ref ws1, name, _, _, ref ws2) => {
+                    if let AstLevel::Nested | AstLevel::Included = level {
                         panic!("blocks ('{}') are only allowed at the top level of a template \
                                 or another block", name);
                     }
-                    self.write_block(ws1, name, ws2);
+                    self.write_block(Some(name), &WS(ws1.0, ws2.1));
+                },
+                Node::Super(ref ws) => {
+                    self.write_block(None, ws);
+                },
+                Node::Include(ref ws, ref include) => {
+                    self.handle_include(ctx, ws, include);
                 },
-                Node::Include(ref ws, path) => {
-                    self.handle_include(state, ws, path);
+                Node::Component(ref ws1, ref component, ref ws2) => {
+                    self.write_component(ctx, ws1, component, ws2);
+                },
+                Node::Push(ref ws1, name, ref nodes, ref ws2) => {
+                    self.write_push(ctx, ws1, name, nodes, ws2);
+                },
+                Node::Stack(ref ws, name) => {
+                    self.write_stack(ws, name);
+                },
+                Node::Once(ref ws1, ref nodes, ref ws2) => {
+                    self.write_once(ctx, ws1, nodes, ws2);
+                },
+                Node::AutoEscape(ref ws1, mode, ref nodes, ref ws2) => {
+                    self.write_autoescape(ctx, ws1, mode, nodes, ws2);
                 },
                 Node::Call(ref ws, scope, name, ref args) => {
-                    self.write_call(state, ws, scope, name, args);
+                    self.write_call(ctx, ws, scope, name, args);
                 },
                 Node::Macro(_, ref m) => {
                     if let AstLevel::Nested = level {
@@ -383,7 +440,7 @@ impl<'a> Generator<'a> {
                     self.handle_ws(ws);
                 },
                 Node::Extends(_) => {
-                    if let AstLevel::Nested = level {
+                    if let AstLevel::Nested | AstLevel::Included = level {
                         panic!("extend blocks only allowed at the top level");
                     }
                     // No whitespace handling: child template top-level is not used,
@@ -393,43 +450,35 @@ impl<'a> Generator<'a> {
         }
     }
 
-    fn write_block_defs(&mut self, state: &'a State) {
-        for b in &state.blocks {
-            if let Node::BlockDef(ref ws1, name, ref nodes, ref ws2) = **b {
-                self.writeln("#[allow(unused_variables)]");
-                self.writeln(&format!(
-                    "fn render_block_{}_into(&self, writer: &mut ::std::fmt::Write) \
-                     -> ::askama::Result<()> {{",
-                    name
-                ));
-                self.prepare_ws(ws1);
-
-                self.locals.push();
-                self.handle(state, nodes, AstLevel::Block);
-                self.locals.pop();
-
-                self.flush_ws(ws2);
-                self.writeln("Ok(())");
-                self.writeln("}");
-            } else {
-                panic!("only block definitions allowed here");
-            }
-        }
-    }
-
-    fn write_cond(&mut self, state: &'a State, conds: &'a [Cond], ws: &WS) {
+    fn write_cond(&mut self, ctx: &'a Context, conds: &'a [Cond], ws: &WS) {
+        let mut start = None;
+        let mut ends = Vec::new();
         for (i, &(ref cws, ref cond, ref nodes)) in conds.iter().enumerate() {
             self.handle_ws(cws);
+            if i == 0 {
+                start = self.html.clone();
+            } else {
+                ends.push(self.html.clone());
+                self.html = start.clone();
+            }
+            self.locals.push();
             match *cond {
-                Some(ref expr) => {
-                    let expr_code = self.visit_expr_root(expr);
+                Some(ref cond) => {
+                    let expr_code = self.visit_expr_root(&cond.expr);
                     if i == 0 {
                         self.write("if ");
                     } else {
                         self.dedent();
                         self.write("} else if ");
                     }
-                    self.write(&expr_code);
+                    if let Some((ref variant, ref params)) = cond.target {
+                        self.write("let ");
+                        self.visit_match_variant(variant);
+                        self.visit_match_params(params);
+                        self.write(&format!(" = (&{}).deref()", expr_code));
+                    } else {
+                        self.write(&expr_code);
+                    }
                 },
                 None => {
                     self.dedent();
@@ -437,15 +486,58 @@ impl<'a> Generator<'a> {
                 },
             }
             self.writeln(" {");
-            self.locals.push();
-            self.handle(state, nodes, AstLevel::Nested);
+            self.handle(ctx, nodes, AstLevel::Nested);
             self.locals.pop();
         }
         self.handle_ws(ws);
         self.writeln("}");
+
+        ends.push(self.html.clone());
+        if conds.last().map_or(false, |&(_, ref cond, _)| cond.is_some()) {
+            ends.push(start);
+        }
+        self.merge_html(ends, "if");
     }
 
-    fn write_match(&mut self, state: &'a State, ws1: &WS, expr: &Expr, inter: Option<&'a str>,
+    // Writes a `let` with a refutable pattern as an `if let`, which renders
+    // the nodes following it if the pattern matches and the `else` body if
+    // not. Whitespace left over at the end of the following nodes is handled
+    // by the enclosing tag, as it would have been without the `let`.
+    fn write_let_else(&mut self, ctx: &'a Context, ws1: &WS, test: &'a CondTest,
+                      nodes_else: &'a [Node], ws2: &WS, rest: &'a [Node], level: AstLevel) {
+        self.flush_ws(ws1);
+        let start = self.html.clone();
+
+        self.locals.push();
+        let expr_code = self.visit_expr_root(&test.expr);
+        self.write("if let ");
+        if let Some((ref variant, ref params)) = test.target {
+            self.visit_match_variant(variant);
+            self.visit_match_params(params);
+        }
+        self.writeln(&format!(" = (&{}).deref() {{", expr_code));
+        self.prepare_ws(ws2);
+        self.handle(ctx, rest, level);
+        let next_ws = self.next_ws.take();
+        self.locals.pop();
+
+        let mut ends = vec![self.html.clone()];
+        self.html = start;
+        self.dedent();
+        self.writeln("} else {");
+        self.prepare_ws(ws1);
+        self.locals.push();
+        self.handle(ctx, nodes_else, AstLevel::Nested);
+        self.flush_ws(ws2);
+        self.locals.pop();
+        self.writeln("}");
+
+        self.next_ws = next_ws;
+        ends.push(self.html.clone());
+        self.merge_html(ends, "let");
+    }
+
+    fn write_match(&mut self, ctx: &'a Context, ws1: &WS, expr: &Expr, inter: Option<&'a str>,
                    arms: &'a [When], ws2: &WS) {
         self.flush_ws(ws1);
         if let Some(inter) = inter {
@@ -456,8 +548,11 @@ impl<'a> Generator<'a> {
 
         let expr_code = self.visit_expr_root(expr);
         self.writeln(&format!("match (&{}).deref() {{", expr_code));
+        let start = self.html.clone();
+        let mut ends = Vec::new();
         for arm in arms {
             let &(ref ws, ref variant, ref params, ref body) = arm;
+            self.html = start.clone();
             self.locals.push();
             match *variant {
                 Some(ref param) => {
@@ -465,34 +560,25 @@ impl<'a> Generator<'a> {
                 },
                 None => self.write("_"),
             };
-            if !params.is_empty() {
-                self.write("(");
-                for (i, param) in params.iter().enumerate() {
-                    if let MatchParameter::Name(p) = *param {
-                        self.locals.insert(p);
-                    }
-                    if i > 0 {
-                        self.write(", ");
-                    }
-                    self.visit_match_param(param);
-                }
-                self.write(")");
-            }
+            self.visit_match_params(params);
             self.writeln(" => {");
             self.handle_ws(ws);
-            self.handle(state, body, AstLevel::Nested);
+            self.handle(ctx, body, AstLevel::Nested);
             self.writeln("}");
             self.locals.pop();
+            ends.push(self.html.clone());
         }
 
         self.writeln("}");
+        self.merge_html(ends, "match");
         self.handle_ws(ws2);
     }
 
-    fn write_loop(&mut self, state: &'a State, ws1: &WS, var: &'a Target, iter: &Expr,
+    fn write_loop(&mut self, ctx: &'a Context, ws1: &WS, var: &'a Target, iter: &Expr,
                   body: &'a [Node], ws2: &WS) {
         self.handle_ws(ws1);
         self.locals.push();
+        let start = self.html.clone();
 
         let expr_code = self.visit_expr_root(iter);
         self.write("for (_loop_index, ");
@@ -503,21 +589,33 @@ impl<'a> Generator<'a> {
         }
         self.writeln(&format!(") in (&{}).into_iter().enumerate() {{", expr_code));
 
-        self.handle(state, body, AstLevel::Nested);
+        self.handle(ctx, body, AstLevel::Nested);
         self.handle_ws(ws2);
         self.writeln("}");
         self.locals.pop();
+
+        let end = self.html.clone();
+        self.merge_html(vec![start, end], "for");
     }
 
-    fn write_call(&mut self, state: &'a State, ws: &WS, scope: Option<&str>, name: &str,
+    fn write_call(&mut self, ctx: &'a Context, ws: &WS, scope: Option<&str>, name: &str,
                   args: &[Expr]) {
-        let def = state.macros.get(&(scope, name)).unwrap_or_else(|| {
-            if let Some(s) = scope {
-                panic!(format!("macro '{}::{}' not found", s, name));
-            } else {
-                panic!(format!("macro '{}' not found", name));
-            }
-        });
+        let contexts = self.contexts;
+        let (ctx, def) = match scope {
+            Some(s) => {
+                let mctx = ctx.find_import(contexts, s).unwrap_or_else(|| {
+                    panic!("no import found for scope '{}'", s);
+                });
+                mctx.find_macro(contexts, name).unwrap_or_else(|| {
+                    panic!(format!("macro '{}::{}' not found", s, name));
+                })
+            },
+            None => {
+                ctx.find_macro(contexts, name).unwrap_or_else(|| {
+                    panic!(format!("macro '{}' not found", name));
+                })
+            },
+        };
 
         self.flush_ws(ws); // Cannot handle_ws() here: whitespace from macro definition comes first
         self.locals.push();
@@ -527,10 +625,11 @@ impl<'a> Generator<'a> {
         for (i, arg) in def.args.iter().enumerate() {
             let expr_code = self.visit_expr_root(args.get(i)
                 .expect(&format!("macro '{}' takes more than {} arguments", name, i)));
+            check_identifier(arg);
             self.write(&format!("let {} = &{};", arg, expr_code));
             self.locals.insert(arg);
         }
-        self.handle(state, &def.nodes, AstLevel::Nested);
+        self.handle(ctx, &def.nodes, AstLevel::Nested);
 
         self.flush_ws(&def.ws2);
         self.writeln("}");
@@ -538,25 +637,146 @@ impl<'a> Generator<'a> {
         self.prepare_ws(ws);
     }
 
-    fn handle_include(&mut self, state: &'a State, ws: &WS, path: &str) {
+    fn handle_include(&mut self, ctx: &'a Context, ws: &WS, include: &'a Include) {
         self.flush_ws(ws);
-        let path = path::find_template_from_path(path, Some(&state.input.path));
-        let src = path::get_template_source(&path);
-        let nodes = parser::parse(&src);
-        let nested = {
-            let mut gen = self.child();
-            gen.handle(state, &nodes, AstLevel::Nested);
-            gen.buf
+        let path = match heritage::find_include(ctx.path, include, self.input.config) {
+            Some(path) => path,
+            None => {
+                self.prepare_ws(ws);
+                return;
+            },
+        };
+        let ctx = &self.contexts[&path];
+
+        let scoped = include.vars.is_some() || include.indent > 0;
+        if scoped {
+            self.writeln("{");
+        }
+        if include.indent > 0 {
+            self.writeln(&format!(
+                "let writer: &mut ::std::fmt::Write = \
+                 &mut ::askama::IndentWriter::new(writer, {}, false);",
+                include.indent
+            ));
+        }
+
+        // With explicitly passed variables, the included template gets its
+        // own scope, holding only those variables.
+        let (nested, html) = match include.vars {
+            Some(ref vars) => {
+                let mut names = Vec::new();
+                let mut values = Vec::new();
+                for &(name, ref value) in vars {
+                    check_identifier(name);
+                    names.push(name);
+                    values.push(format!("&{}", self.visit_expr_root(value)));
+                }
+                self.writeln(&format!("let ({},) = ({},);", names.join(", "), values.join(", ")));
+
+                let mut gen = Self::new(self.input, self.contexts, self.heritage,
+                                        SetChain::new(), self.indent);
+                gen.super_block = self.super_block;
+                gen.in_fragment = self.in_fragment;
+                gen.html = self.html.clone();
+                gen.escaping = self.escaping.clone();
+                for name in names {
+                    gen.locals.insert(name);
+                }
+                gen.handle(ctx, ctx.nodes, AstLevel::Included);
+                (gen.buf, gen.html)
+            },
+            None => {
+                let mut gen = self.child();
+                gen.handle(ctx, ctx.nodes, AstLevel::Included);
+                (gen.buf, gen.html)
+            },
         };
+        self.html = html;
         self.buf.push_str(&nested);
+        if scoped {
+            self.writeln("}");
+        }
         self.prepare_ws(ws);
     }
 
-    fn write_let_decl(&mut self, ws: &WS, var: &'a Target) {
+    // Renders a component, a struct implementing `Template`, into the current
+    // writer. The component's body is rendered first and passed as `slot`.
+    fn write_component(&mut self, ctx: &'a Context, ws1: &WS, component: &'a Component,
+                       ws2: &WS) {
+        self.handle_ws(ws1);
+        let mut fields = Vec::new();
+        for &(name, ref value) in &component.fields {
+            check_identifier(name);
+            fields.push(format!("{}: {}", name, self.visit_expr_root(value)));
+        }
+
+        self.writeln("{");
+        if component.nodes.is_empty() {
+            self.handle_ws(ws2);
+        } else {
+            self.write_fragment(ctx, &component.nodes, ws2);
+            fields.push("slot: ::askama::PreEscaped(askama_fragment)".to_owned());
+        }
+        self.writeln(&format!(
+            "::askama::Template::render_into_stacks(&{} {{ {} }}, writer, askama_stacks)?;",
+            component.path.join("::"), fields.join(", ")
+        ));
+        self.writeln("}");
+    }
+
+    // Renders the given nodes into a separate `String`, `askama_fragment`.
+    fn write_fragment(&mut self, ctx: &'a Context, nodes: &'a [Node], ws: &WS) {
+        self.writeln("let mut askama_fragment = String::new();");
+        self.writeln("{");
+        self.writeln("let writer: &mut ::std::fmt::Write = &mut askama_fragment;");
+        let prev_in_fragment = self.in_fragment;
+        self.in_fragment = true;
+        let prev_html = self.html.take();
+        if prev_html.is_some() {
+            self.html = Some(HtmlContext::new());
+        }
+        self.locals.push();
+        self.handle(ctx, nodes, AstLevel::Nested);
+        self.handle_ws(ws);
+        self.locals.pop();
+        self.html = prev_html;
+        self.in_fragment = prev_in_fragment;
+        self.writeln("}");
+    }
+
+    fn write_push(&mut self, ctx: &'a Context, ws1: &WS, name: &str, nodes: &'a [Node],
+                  ws2: &WS) {
+        self.handle_ws(ws1);
+        self.writeln("{");
+        self.write_fragment(ctx, nodes, ws2);
+        self.writeln(&format!("askama_stacks.push({:?}, askama_fragment);", name));
+        self.writeln("}");
+    }
+
+    fn write_stack(&mut self, ws: &WS, name: &str) {
+        if self.in_fragment {
+            panic!("stack '{}' cannot be used inside push, once or component bodies", name);
+        }
+        self.handle_ws(ws);
+        self.writeln(&format!("askama_buf.mark({:?});", name));
+    }
+
+    fn write_once(&mut self, ctx: &'a Context, ws1: &WS, nodes: &'a [Node], ws2: &WS) {
+        self.handle_ws(ws1);
+        self.writeln("{");
+        self.write_fragment(ctx, nodes, ws2);
+        self.writeln("if askama_stacks.once(&askama_fragment) {");
+        self.writeln("writer.write_str(&askama_fragment)?;");
+        self.writeln("}");
+        self.writeln("}");
+    }
+
+    fn write_let_decl(&mut self, ws: &WS, mutable: bool, var: &'a Target) {
         self.handle_ws(ws);
-        self.write("let ");
+        self.write(if mutable { "let mut " } else { "let " });
         match *var {
             Target::Name(name) => {
+                check_identifier(name);
                 self.locals.insert(name);
                 self.write(name);
             },
@@ -564,14 +784,18 @@ impl<'a> Generator<'a> {
         self.writeln(";");
     }
 
-    fn write_let(&mut self, ws: &WS, var: &'a Target, val: &Expr) {
+    fn write_let(&mut self, ws: &WS, mutable: bool, var: &'a Target, val: &Expr) {
         self.handle_ws(ws);
         let mut code = String::new();
         self.visit_expr(val, &mut code);
 
         match *var {
             Target::Name(name) => {
-                if !self.locals.contains(name) {
+                check_identifier(name);
+                if mutable {
+                    self.write("let mut ");
+                    self.locals.insert(name);
+                } else if !self.locals.contains(name) {
                     self.write("let ");
                     self.locals.insert(name);
                 }
@@ -581,33 +805,155 @@ impl<'a> Generator<'a> {
         self.write(&format!(" = {};", &code));
     }
 
-    fn write_block(&mut self, ws1: &WS, name: &str, ws2: &WS) {
-        self.flush_ws(ws1);
-        let ctx = if self.impl_blocks {
-            "timpl"
-        } else {
-            "self"
+    fn write_set(&mut self, ws: &WS, var: &'a Target, val: &Expr) {
+        self.handle_ws(ws);
+        let mut code = String::new();
+        self.visit_expr(val, &mut code);
+
+        match *var {
+            Target::Name(name) => {
+                if !self.locals.contains(name) {
+                    panic!("cannot set '{}', which is not a local variable", name);
+                }
+                self.write(name);
+            },
+        }
+        self.writeln(&format!(" = {};", &code));
+    }
+
+    fn write_with(&mut self, ctx: &'a Context, ws1: &WS, vars: &'a [(bool, &str, Expr)],
+                  nodes: &'a [Node], ws2: &WS) {
+        self.handle_ws(ws1);
+        let mut targets = Vec::new();
+        let mut values = Vec::new();
+        for &(mutable, name, ref value) in vars {
+            check_identifier(name);
+            targets.push(if mutable { format!("mut {}", name) } else { name.to_owned() });
+            values.push(self.visit_expr_root(value));
+        }
+
+        self.writeln("{");
+        self.writeln(&format!("let ({},) = ({},);", targets.join(", "), values.join(", ")));
+        self.locals.push();
+        for &(_, name, _) in vars {
+            self.locals.insert(name);
+        }
+        self.handle(ctx, nodes, AstLevel::Nested);
+        self.handle_ws(ws2);
+        self.locals.pop();
+        self.writeln("}");
+    }
+
+    // Renders the nodes with the given escape mode, where `true` stands for
+    // HTML and `false` for no escaping.
+    fn write_autoescape(&mut self, ctx: &'a Context, ws1: &WS, mode: &str, nodes: &'a [Node],
+                        ws2: &WS) {
+        self.handle_ws(ws1);
+        let mode = match mode {
+            "true" => EscapeMode::Html,
+            "false" => EscapeMode::None,
+            mode => EscapeMode::from(mode.to_owned()),
         };
-        self.writeln(&format!("{}.render_block_{}_into(writer)?;", ctx, name));
-        self.prepare_ws(ws2);
+        let prev_escaping = mem::replace(&mut self.escaping, mode);
+        // Outside of HTML templates, the HTML context is only tracked
+        // within the region.
+        let track_html = self.html.is_none() && self.escaping == EscapeMode::Html;
+        if track_html {
+            self.html = Some(HtmlContext::new());
+        }
+
+        self.writeln("{");
+        self.locals.push();
+        self.handle(ctx, nodes, AstLevel::Nested);
+        self.handle_ws(ws2);
+        self.locals.pop();
+        self.writeln("}");
+
+        if track_html {
+            self.html = None;
+        }
+        self.escaping = prev_escaping;
+    }
+
+    // Renders the most derived definition of the named block, or, if no name
+    // is given, the parent definition of the block that is being rendered.
+    fn write_block(&mut self, name: Option<&'a str>, outer: &WS) {
+        self.flush_ws(outer);
+        let prev_block = self.super_block;
+        let cur = match (name, prev_block) {
+            (Some(cur_name), Some((prev_name, _))) if cur_name == prev_name => {
+                panic!("cannot define recursive blocks ('{}')", cur_name);
+            },
+            (Some(cur_name), _) => (cur_name, 0),
+            (None, Some((prev_name, gen))) => (prev_name, gen + 1),
+            (None, None) => panic!("cannot call 'super()' outside block"),
+        };
+        self.super_block = Some(cur);
+
+        let heritage = self.heritage;
+        let (ctx, def) = *heritage.blocks[cur.0].get(cur.1).unwrap_or_else(|| {
+            panic!("no parent definition found for block '{}' to call 'super()'", cur.0);
+        });
+        if let Node::BlockDef(ref ws1, _, _, ref nodes, ref ws2) = *def {
+            self.prepare_ws(ws1);
+            self.locals.push();
+            self.handle(ctx, nodes, AstLevel::Block);
+            self.locals.pop();
+            self.flush_ws(ws2);
+        } else {
+            panic!("only block definitions allowed here");
+        }
+
+        self.super_block = prev_block;
+        self.prepare_ws(outer);
     }
 
-    fn write_expr(&mut self, state: &'a State, ws: &WS, s: &Expr) {
+    fn write_expr(&mut self, ws: &WS, s: &Expr) {
         self.handle_ws(ws);
         let mut code = String::new();
         let wrapped = self.visit_expr(s, &mut code);
-        self.writeln(&format!("let askama_expr = &{};", code));
+        self.writeln(&format!("let askama_expr = &({});", code));
 
-        use self::DisplayWrap::*;
-        use super::input::EscapeMode::*;
         self.write("writer.write_fmt(format_args!(\"{}\", ");
-        self.write(match (wrapped, &state.input.meta.escaping) {
-            (Wrapped, &Html) |
-            (Wrapped, &None) |
-            (Unwrapped, &None) => "askama_expr",
-            (Unwrapped, &Html) => "&::askama::MarkupDisplay::from(askama_expr)",
-        });
+        if wrapped == DisplayWrap::Unwrapped && self.escaping != EscapeMode::None {
+            let escaper = self.context_escaper();
+            // In HTML text and plain attribute values, values of types
+            // implementing `Markup` or `HtmlSafe` are written as they are,
+            // which `select()` takes care of. Other contexts, like scripts or
+            // URLs, always escape.
+            if escaper == EscapeMode::Html.escaper() {
+                self.write(&format!(
+                    "&(&&::askama::escaping::Select(askama_expr)).select({})",
+                    escaper
+                ));
+            } else {
+                self.write(&format!(
+                    "&::askama::MarkupDisplay::new_unsafe(askama_expr, {})",
+                    escaper
+                ));
+            }
+        } else {
+            self.write("askama_expr");
+        }
         self.writeln("))?;");
+        if let Some(ref mut html) = self.html {
+            html.feed_expr();
+        }
+    }
+
+    // Returns the escaper for an expression at the current position: in HTML,
+    // the one for the context around it, otherwise the template's escaper.
+    fn context_escaper(&self) -> String {
+        match self.html {
+            Some(ref context) if self.escaping == EscapeMode::Html => {
+                context.escaper().unwrap_or_else(|context| {
+                    panic!("cannot safely escape expression {} in template {:?}; \
+                            use the 'safe' filter if the value is trusted",
+                           context, self.input.path);
+                })
+            },
+            _ => self.escaping.escaper(),
+        }.to_owned()
     }
 
     fn write_lit(&mut self, lws: &'a str, val: &str, rws: &'a str) {
@@ -619,17 +965,25 @@ impl<'a> Generator<'a> {
                 assert!(rws.is_empty());
                 self.next_ws = Some(lws);
             } else {
-                self.writeln(&format!("writer.write_str({:#?})?;", lws));
+                self.write_str_lit(lws);
             }
         }
         if !val.is_empty() {
-            self.writeln(&format!("writer.write_str({:#?})?;", val));
+            self.write_str_lit(val);
         }
         if !rws.is_empty() {
             self.next_ws = Some(rws);
         }
     }
 
+    // Writes literal template output, keeping track of its HTML context.
+    fn write_str_lit(&mut self, s: &str) {
+        self.writeln(&format!("writer.write_str({:#?})?;", s));
+        if let Some(ref mut html) = self.html {
+            html.feed(s);
+        }
+    }
+
     fn write_comment(&mut self, ws: &WS) {
         self.handle_ws(ws);
     }
@@ -649,6 +1003,11 @@ impl<'a> Generator<'a> {
             Expr::Var(s) => self.visit_var(s, code),
             Expr::Path(ref path) => self.visit_path(path, code),
             Expr::Array(ref elements) => self.visit_array(elements, code),
+            Expr::Attr(ref obj, _) |
+            Expr::MethodCall(ref obj, _, _) if is_opt_chain(obj) => {
+                self.visit_opt_chain(expr, code)
+            },
+            Expr::OptAttr(_, _) | Expr::OptMethodCall(_, _, _) => self.visit_opt_chain(expr, code),
             Expr::Attr(ref obj, name) => self.visit_attr(obj, name, code),
             Expr::Filter(name, ref args) => self.visit_filter(name, args, code),
             Expr::Unary(op, ref inner) => self.visit_unary(op, inner, code),
@@ -657,6 +1016,7 @@ impl<'a> Generator<'a> {
             Expr::MethodCall(ref obj, method, ref args) => {
                 self.visit_method_call(obj, method, args, code)
             },
+            Expr::Try(ref inner) => self.visit_try(inner, code),
         }
     }
 
@@ -684,12 +1044,30 @@ impl<'a> Generator<'a> {
         wrapped
     }
 
+    fn visit_match_params(&mut self, params: &'a [MatchParameter]) {
+        if params.is_empty() {
+            return;
+        }
+        self.write("(");
+        for (i, param) in params.iter().enumerate() {
+            if let MatchParameter::Name(p) = *param {
+                self.locals.insert(p);
+            }
+            if i > 0 {
+                self.write(", ");
+            }
+            self.visit_match_param(param);
+        }
+        self.write(")");
+    }
+
     fn visit_match_param(&mut self, param: &MatchParameter) -> DisplayWrap {
         let mut code = String::new();
         let wrapped = match *param {
             MatchParameter::NumLit(s) => self.visit_num_lit(s, &mut code),
             MatchParameter::StrLit(s) => self.visit_str_lit(s, &mut code),
             MatchParameter::Name(s) => {
+                check_identifier(s);
                 code.push_str("ref ");
                 code.push_str(s);
                 DisplayWrap::Unwrapped
@@ -708,13 +1086,33 @@ impl<'a> Generator<'a> {
             return DisplayWrap::Unwrapped;
         }
 
-        if filters::BUILT_IN_FILTERS.contains(&name) {
+        if name == "safe" || name == "escape" || name == "e" || name == "json" {
+            // These filters are passed the active escaper. Explicit
+            // escaping in templates without escaping escapes HTML.
+            let escaper = match self.escaping {
+                EscapeMode::None if name == "escape" || name == "e" => {
+                    EscapeMode::Html.escaper().to_owned()
+                },
+                _ if name == "escape" || name == "e" => self.context_escaper(),
+                ref escaping => escaping.escaper().to_owned(),
+            };
+            code.push_str(&format!("::askama::filters::{}({}, &", name, escaper));
+        } else if filters::BUILT_IN_FILTERS.contains(&name) {
             code.push_str(&format!("::askama::filters::{}(&", name));
         } else {
             code.push_str(&format!("filters::{}(&", name));
         }
 
         self._visit_args(args, code);
+        if name == "indent" {
+            // `first` is positional only, so it can be added when left out.
+            match args.len() {
+                2 => code.push_str(", &false"),
+                3 => {},
+                _ => panic!("the indent filter takes a width and optionally `first`: \
+                             indent(4) or indent(4, true)"),
+            }
+        }
         code.push_str(")?");
         if name == "safe" || name == "escape" || name == "e" || name == "json" {
             DisplayWrap::Wrapped
@@ -749,23 +1147,27 @@ impl<'a> Generator<'a> {
             if i > 0 {
                 code.push_str(", &");
             }
+            self._visit_arg(arg, code);
+        }
+    }
 
-            let intercept = match *arg {
-                Expr::Filter(_, _) | Expr::MethodCall(_, _, _) => true,
-                _ => false,
-            };
+    fn _visit_arg(&mut self, arg: &Expr, code: &mut String) {
+        let intercept = match *arg {
+            Expr::Filter(_, _) | Expr::MethodCall(_, _, _) | Expr::Try(_) => true,
+            Expr::OptAttr(_, _) | Expr::OptMethodCall(_, _, _) => true,
+            _ => false,
+        };
 
-            if intercept {
-                let offset = code.len();
-                self.visit_expr(arg, code);
-                let idx = self.vars;
-                self.vars += 1;
-                self.writeln(&format!("let var{} = {};", idx, &code[offset..]));
-                code.truncate(offset);
-                code.push_str(&format!("var{}", idx));
-            } else {
-                self.visit_expr(arg, code);
-            }
+        if intercept {
+            let offset = code.len();
+            self.visit_expr(arg, code);
+            let idx = self.vars;
+            self.vars += 1;
+            self.writeln(&format!("let var{} = {};", idx, &code[offset..]));
+            code.truncate(offset);
+            code.push_str(&format!("var{}", idx));
+        } else {
+            self.visit_expr(arg, code);
         }
     }
 
@@ -784,6 +1186,7 @@ impl<'a> Generator<'a> {
             }
         }
         self.visit_expr(obj, code);
+        check_identifier(attr);
         code.push_str(&format!(".{}", attr));
         DisplayWrap::Unwrapped
     }
@@ -796,12 +1199,85 @@ impl<'a> Generator<'a> {
             self.visit_expr(obj, code);
         }
 
+        check_identifier(method);
         code.push_str(&format!(".{}(", method));
         self._visit_args(args, code);
         code.push_str(")");
         DisplayWrap::Unwrapped
     }
 
+    // Errors are boxed into `ErrorKind::Custom`, so `?` works for any error
+    // type that can be converted into a `Box<Error + Send + Sync>`.
+    fn visit_try(&mut self, inner: &Expr, code: &mut String) -> DisplayWrap {
+        self.visit_expr(inner, code);
+        code.push_str(".map_err(|err| ::askama::Error::from(\
+                       ::askama::ErrorKind::Custom(err.into())))?");
+        DisplayWrap::Unwrapped
+    }
+
+    // Lowers an attribute chain containing `&.` into `Option` combinators. The
+    // chain is split at every `&.`; each part but the last is flattened with
+    // `and_then()`. The last one is mapped to a reference to its value, which
+    // is flattened as well for fields, if the field is an `Option`.
+    fn visit_opt_chain(&mut self, expr: &Expr, code: &mut String) -> DisplayWrap {
+        let mut links = Vec::new();
+        let mut cur = expr;
+        loop {
+            let link = match *cur {
+                Expr::Attr(ref obj, name) => (obj, false, name, None),
+                Expr::MethodCall(ref obj, name, ref args) => (obj, false, name, Some(args)),
+                Expr::OptAttr(ref obj, name) => (obj, true, name, None),
+                Expr::OptMethodCall(ref obj, name, ref args) => (obj, true, name, Some(args)),
+                _ => break,
+            };
+            cur = link.0;
+            links.push(link);
+        }
+
+        // Anything below the innermost `&.` is evaluated as usual.
+        let innermost = links.iter().rposition(|link| link.1).unwrap();
+        links.truncate(innermost + 1);
+        links.reverse();
+        self.visit_expr(links[0].0, code);
+        code.push_str(".as_ref()");
+
+        let mut links = links.into_iter().peekable();
+        while let Some((_, _, name, args)) = links.next() {
+            let mut body = String::from("askama_opt");
+            let mut is_attr = self._visit_link(name, args, &mut body);
+            while links.peek().map_or(false, |link| !link.1) {
+                let (_, _, name, args) = links.next().unwrap();
+                is_attr = self._visit_link(name, args, &mut body);
+            }
+            code.push_str(&match (links.peek().is_some(), is_attr) {
+                (true, true) => format!(".and_then(|askama_opt| {}.as_ref())", body),
+                (true, false) => format!(".and_then(|askama_opt| {})", body),
+                // A last field that is an `Option` itself is flattened.
+                (false, true) => {
+                    format!(".and_then(|askama_opt| (&&::askama::chain::Lift(&{})).lift())", body)
+                },
+                (false, false) => format!(".map(|askama_opt| {})", body),
+            });
+        }
+        DisplayWrap::Unwrapped
+    }
+
+    // Writes a single attribute access or method call in an optional chain,
+    // returning whether it was an attribute access.
+    fn _visit_link(&mut self, name: &str, args: Option<&Vec<Expr>>, code: &mut String) -> bool {
+        check_identifier(name);
+        code.push_str(&format!(".{}", name));
+        match args {
+            Some(args) => {
+                code.push_str("(");
+                self._visit_args(args, code);
+                code.push_str(")");
+                false
+            },
+            None => true,
+        }
+    }
+
     fn visit_unary(&mut self, op: &str, inner: &Expr, code: &mut String) -> DisplayWrap {
         code.push_str(op);
         self.visit_expr(inner, code);
@@ -810,12 +1286,64 @@ impl<'a> Generator<'a> {
 
     fn visit_binop(&mut self, op: &str, left: &Expr, right: &Expr, code: &mut String)
                    -> DisplayWrap {
+        if op == "~" {
+            return self._visit_concat(left, right, code);
+        } else if op == "??" {
+            code.push_str("::askama::filters::default(&");
+            self._visit_arg(left, code);
+            code.push_str(", &");
+            self._visit_arg(right, code);
+            code.push_str(")?");
+            return DisplayWrap::Unwrapped;
+        }
+
         self.visit_expr(left, code);
         code.push_str(&format!(" {} ", op));
         self.visit_expr(right, code);
         DisplayWrap::Unwrapped
     }
 
+    // Concatenates the `Display` output of all operands of a chain of `~`.
+    // If any operand is already escaped while escaping is enabled, the others
+    // are escaped individually and the result is marked as safe.
+    fn _visit_concat(&mut self, left: &Expr, right: &Expr, code: &mut String) -> DisplayWrap {
+        let mut operands = vec![left];
+        let mut cur = right;
+        while let Expr::BinOp("~", ref left, ref right) = *cur {
+            operands.push(left);
+            cur = right;
+        }
+        operands.push(cur);
+
+        let parts = operands.into_iter().map(|expr| {
+            let mut part = String::new();
+            let wrapped = self.visit_expr(expr, &mut part);
+            (part, wrapped)
+        }).collect::<Vec<_>>();
+        let escape = self.escaping != EscapeMode::None &&
+            parts.iter().any(|&(_, wrapped)| wrapped == DisplayWrap::Wrapped);
+        let escaper = if escape { self.context_escaper() } else { String::new() };
+
+        let mut args = String::new();
+        for &(ref part, wrapped) in &parts {
+            args.push_str(", ");
+            if escape && wrapped == DisplayWrap::Unwrapped {
+                args.push_str(&format!("::askama::MarkupDisplay::new_unsafe(&{}, {})",
+                                       part, escaper));
+            } else {
+                args.push_str(part);
+            }
+        }
+        let format = format!("format!(\"{}\"{})", "{}".repeat(parts.len()), args);
+        if escape {
+            code.push_str(&format!("::askama::MarkupDisplay::new_safe({}, {})", format, escaper));
+            DisplayWrap::Wrapped
+        } else {
+            code.push_str(&format);
+            DisplayWrap::Unwrapped
+        }
+    }
+
     fn visit_group(&mut self, inner: &Expr, code: &mut String) -> DisplayWrap {
         code.push_str("(");
         self.visit_expr(inner, code);
@@ -846,6 +1374,11 @@ impl<'a> Generator<'a> {
     }
 
     fn visit_var(&mut self, s: &str, code: &mut String) -> DisplayWrap {
+        if s == "self" || s == "true" || s == "false" {
+            code.push_str(s);
+            return DisplayWrap::Unwrapped;
+        }
+        check_identifier(s);
         if self.locals.contains(s) {
             code.push_str(s);
         } else {
@@ -865,6 +1398,7 @@ impl<'a> Generator<'a> {
     }
 
     fn visit_target_single<'t>(&mut self, name: &'t str) -> Vec<&'t str> {
+        check_identifier(name);
         vec![name]
     }
 
@@ -874,6 +1408,29 @@ impl<'a> Generator<'a> {
         }
     }
 
+    /* Helper methods for tracking the HTML context */
+
+    // Starts a new output, for which the HTML context is at the top level.
+    fn reset_html(&mut self) {
+        if self.html.is_some() {
+            self.html = Some(HtmlContext::new());
+        }
+    }
+
+    // Continues with the HTML context in which all branches of a `tag` end,
+    // which must be the same for all of them.
+    fn merge_html(&mut self, ends: Vec<Option<HtmlContext>>, tag: &str) {
+        if self.html.is_none() {
+            return;
+        }
+        let ends = ends.into_iter().map(|html| html.unwrap()).collect();
+        match HtmlContext::merge(ends) {
+            Ok(html) => self.html = Some(html),
+            Err(()) => panic!("branches of '{}' tag end in different HTML contexts in template {:?}",
+                              tag, self.input.path),
+        }
+    }
+
     /* Helper methods for dealing with whitespace nodes */
 
     // Combines `flush_ws()` and `prepare_ws()` to handle both trailing whitespace from the
@@ -886,11 +1443,12 @@ impl<'a> Generator<'a> {
     // If the previous literal left some trailing whitespace in `next_ws` and the
     // prefix whitespace suppressor from the given argument, flush that whitespace.
     // In either case, `next_ws` is reset to `None` (no trailing whitespace).
+    // Whitespace is always suppressed if the template is configured to do so.
     fn flush_ws(&mut self, ws: &WS) {
-        if self.next_ws.is_some() && !ws.0 {
+        if self.next_ws.is_some() && !ws.0 && !self.input.meta.suppress_whitespace {
             let val = self.next_ws.unwrap();
             if !val.is_empty() {
-                self.writeln(&format!("writer.write_str({:#?})?;", val));
+                self.write_str_lit(val);
             }
         }
         self.next_ws = None;
@@ -900,7 +1458,7 @@ impl<'a> Generator<'a> {
     // argument, to determine whether to suppress leading whitespace from the
     // next literal.
     fn prepare_ws(&mut self, ws: &WS) {
-        self.skip_ws = ws.1;
+        self.skip_ws = ws.1 || self.input.meta.suppress_whitespace;
     }
 
     /* Helper methods for writing to internal buffer */
@@ -942,6 +1500,50 @@ impl<'a> Generator<'a> {
     }
 }
 
+// Panics for names that would not be valid identifiers in the generated code:
+// Rust keywords must be written as raw identifiers (`r#type`), and some
+// keywords cannot be used as identifiers at all.
+fn check_identifier(name: &str) {
+    if name.starts_with("r#") {
+        let name = &name[2..];
+        if ["_", "crate", "self", "Self", "super"].contains(&name) {
+            panic!("'{}' cannot be used as a raw identifier", name);
+        }
+    } else if RUST_KEYWORDS.contains(&name) {
+        panic!("'{}' is a reserved word in Rust; use 'r#{}' to refer to it", name, name);
+    }
+}
+
+// Whether the given nodes contain a `stack` tag, including in nested blocks.
+fn has_stack(nodes: &[Node]) -> bool {
+    nodes.iter().any(|n| match *n {
+        Node::Stack(_, _) => true,
+        Node::Cond(ref conds, _) => conds.iter().any(|&(_, _, ref nodes)| has_stack(nodes)),
+        Node::Match(_, _, _, ref arms, _) => {
+            arms.iter().any(|&(_, _, _, ref nodes)| has_stack(nodes))
+        },
+        Node::Loop(_, _, _, ref nodes, _) |
+        Node::BlockDef(_, _, _, ref nodes, _) |
+        Node::Push(_, _, ref nodes, _) |
+        Node::With(_, _, ref nodes, _) |
+        Node::Once(_, ref nodes, _) |
+        Node::LetElse(_, _, ref nodes, _) |
+        Node::AutoEscape(_, _, ref nodes, _) => has_stack(nodes),
+        Node::Macro(_, ref def) => has_stack(&def.nodes),
+        Node::Component(_, ref component, _) => has_stack(&component.nodes),
+        _ => false,
+    })
+}
+
+// Whether the given expression contains a `&.` attribute access in its chain.
+fn is_opt_chain(expr: &Expr) -> bool {
+    match *expr {
+        Expr::OptAttr(_, _) | Expr::OptMethodCall(_, _, _) => true,
+        Expr::Attr(ref obj, _) | Expr::MethodCall(ref obj, _, _) => is_opt_chain(obj),
+        _ => false,
+    }
+}
+
 struct SetChain<'a, T: 'a> where T: cmp::Eq + hash::Hash {
     parent: Option<&'a SetChain<'a, T>>,
     scopes: Vec<HashSet<T>>,
@@ -976,12 +1578,13 @@ impl<'a, T: 'a> SetChain<'a, T> where T: cmp::Eq + hash::Hash {
 enum AstLevel {
     Top,
     Block,
+    Included,
     Nested,
 }
 
 impl Copy for AstLevel {}
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 enum DisplayWrap {
     Wrapped,
     Unwrapped,
@@ -989,4 +1592,10 @@ enum DisplayWrap {
 
 impl Copy for DisplayWrap {}
 
-type MacroMap<'a> = HashMap<(Option<&'a str>, &'a str), &'a Macro<'a>>;
+const RUST_KEYWORDS: [&str; 51] = [
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in",
+    "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
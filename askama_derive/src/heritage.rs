@@ -0,0 +1,215 @@
+use parser::{Expr, Include, Macro, Node};
+use shared::config::Config;
+use shared::path;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+
+// The parsed contents of a single template file, as far as they are relevant
+// to other templates: its parent, its blocks, its macros and the templates
+// it includes or imports.
+pub struct Context<'a> {
+    pub path: &'a Path,
+    pub nodes: &'a [Node<'a>],
+    pub extends: Option<PathBuf>,
+    pub blocks: HashMap<&'a str, &'a Node<'a>>,
+    pub macros: HashMap<&'a str, &'a Macro<'a>>,
+    pub imports: HashMap<&'a str, PathBuf>,
+    pub includes: Vec<PathBuf>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new<'n>(path: &'n Path, nodes: &'n [Node<'n>], config: &Config) -> Context<'n> {
+        let mut extends = None;
+        let mut blocks = Vec::new();
+        let mut macros = HashMap::new();
+        let mut imports = HashMap::new();
+        let mut includes = Vec::new();
+        find_includes(path, nodes, config, &mut includes);
+
+        for n in nodes {
+            match n {
+                Node::Extends(Expr::StrLit(extends_path)) => match extends {
+                    Some(_) => panic!("multiple extend blocks found"),
+                    None => {
+                        extends = Some(path::find_template_from_path(extends_path, Some(path), config));
+                    },
+                },
+                def @ Node::BlockDef(_, _, _, _, _) => {
+                    blocks.push(def);
+                },
+                Node::Macro(name, m) => {
+                    macros.insert(*name, m);
+                },
+                Node::Import(_, import_path, scope) => {
+                    let path = path::find_template_from_path(import_path, Some(path), config);
+                    imports.insert(*scope, path);
+                },
+                _ => {},
+            }
+        }
+
+        let mut check_nested = 0;
+        let mut nested_blocks = Vec::new();
+        while check_nested < blocks.len() {
+            if let Node::BlockDef(_, _, _, ref nodes, _) = blocks[check_nested] {
+                for n in nodes {
+                    if let def @ Node::BlockDef(_, _, _, _, _) = n {
+                        nested_blocks.push(def);
+                    }
+                }
+            } else {
+                panic!("non block found in list of blocks");
+            }
+            blocks.append(&mut nested_blocks);
+            check_nested += 1;
+        }
+
+        let blocks = blocks.iter().map(|def| {
+            if let Node::BlockDef(_, name, _, _, _) = def {
+                (*name, *def)
+            } else {
+                unreachable!()
+            }
+        }).collect();
+
+        Context { path, nodes, extends, blocks, macros, imports, includes }
+    }
+
+    // Returns the templates this template depends on: its parent and the
+    // templates it includes or imports.
+    pub fn dependencies(&self) -> Vec<&PathBuf> {
+        self.extends.iter().chain(self.imports.values()).chain(self.includes.iter()).collect()
+    }
+
+    // Finds the macro with the given name visible from this template, along
+    // with the context of the template defining it. Macros defined in the
+    // template itself take precedence over those of its parent and included
+    // templates, which are searched transitively.
+    pub fn find_macro(&'a self, contexts: &'a Contexts<'a>, name: &str)
+                      -> Option<(&'a Context<'a>, &'a Macro<'a>)> {
+        let what = format!("macro '{}'", name);
+        self.lookup(contexts, &mut Vec::new(), &what, &|ctx: &'a Context<'a>| {
+            ctx.macros.get(name).map(|def| (ctx.path, (ctx, *def)))
+        }).map(|(_, found)| found)
+    }
+
+    // Finds the context of the template imported with the given scope name,
+    // following the parent and included templates like `find_macro()`.
+    pub fn find_import(&'a self, contexts: &'a Contexts<'a>, scope: &str)
+                       -> Option<&'a Context<'a>> {
+        let what = format!("import scope '{}'", scope);
+        self.lookup(contexts, &mut Vec::new(), &what, &|ctx: &'a Context<'a>| {
+            ctx.imports.get(scope).map(|path| (path.as_path(), &contexts[path]))
+        }).map(|(_, found)| found)
+    }
+
+    // Searches this template and the templates it extends or includes, using
+    // `find` to look for a definition. Along with the definition, `find`
+    // returns the path identifying it, so that the same definition reached
+    // through different templates is not mistaken for a name clash.
+    fn lookup<T, F>(&'a self, contexts: &'a Contexts<'a>, stack: &mut Vec<&'a Path>,
+                    what: &str, find: &F) -> Option<(&'a Path, T)>
+            where F: Fn(&'a Context<'a>) -> Option<(&'a Path, T)> {
+        if stack.contains(&self.path) {
+            panic!("cyclic dependency between templates found at {:?}", self.path);
+        }
+        if let Some(found) = find(self) {
+            return Some(found);
+        }
+
+        stack.push(self.path);
+        let mut found: Option<(&'a Path, T)> = None;
+        for path in self.extends.iter().chain(self.includes.iter()) {
+            let inner = contexts[path].lookup(contexts, stack, what, find);
+            match (&found, inner) {
+                (&Some((prev, _)), Some((cur, _))) if prev != cur => {
+                    panic!("{} is defined in both {:?} and {:?}, as used by {:?}",
+                           what, prev, cur, self.path);
+                },
+                (&None, inner @ Some(_)) => found = inner,
+                _ => {},
+            }
+        }
+        stack.pop();
+        found
+    }
+}
+
+// Finds the template included from the template at `path`, which may only be
+// missing if the include was marked with `ignore missing`.
+pub fn find_include(path: &Path, include: &Include, config: &Config) -> Option<PathBuf> {
+    if include.ignore_missing {
+        path::find_template(include.path, Some(path), config)
+    } else {
+        Some(path::find_template_from_path(include.path, Some(path), config))
+    }
+}
+
+// Collects the paths of all templates included anywhere in the given nodes.
+fn find_includes(path: &Path, nodes: &[Node], config: &Config, includes: &mut Vec<PathBuf>) {
+    for n in nodes {
+        match *n {
+            Node::Include(_, ref include) => {
+                if let Some(include_path) = find_include(path, include, config) {
+                    if !includes.contains(&include_path) {
+                        includes.push(include_path);
+                    }
+                }
+            },
+            Node::Cond(ref conds, _) => for &(_, _, ref nodes) in conds {
+                find_includes(path, nodes, config, includes);
+            },
+            Node::Match(_, _, _, ref arms, _) => for &(_, _, _, ref nodes) in arms {
+                find_includes(path, nodes, config, includes);
+            },
+            Node::Loop(_, _, _, ref nodes, _) |
+            Node::BlockDef(_, _, _, ref nodes, _) |
+            Node::Push(_, _, ref nodes, _) |
+            Node::With(_, _, ref nodes, _) |
+            Node::Once(_, ref nodes, _) |
+            Node::LetElse(_, _, ref nodes, _) |
+            Node::AutoEscape(_, _, ref nodes, _) => find_includes(path, nodes, config, includes),
+            Node::Macro(_, ref def) => find_includes(path, &def.nodes, config, includes),
+            Node::Component(_, ref component, _) => {
+                find_includes(path, &component.nodes, config, includes)
+            },
+            _ => {},
+        }
+    }
+}
+
+// The inheritance chain for a template: the root template, of which the nodes
+// are rendered, and all definitions for each block, the most derived first.
+pub struct Heritage<'a> {
+    pub root: &'a Context<'a>,
+    pub blocks: BlockAncestry<'a>,
+}
+
+impl<'a> Heritage<'a> {
+    pub fn new<'n>(mut ctx: &'n Context<'n>, contexts: &'n Contexts<'n>) -> Heritage<'n> {
+        let mut blocks: BlockAncestry<'n> = HashMap::new();
+        let mut seen = Vec::new();
+        loop {
+            for (name, def) in &ctx.blocks {
+                blocks.entry(*name).or_insert_with(Vec::new).push((ctx, *def));
+            }
+            match ctx.extends {
+                Some(ref path) => {
+                    if seen.contains(&path) {
+                        panic!("cyclic template inheritance found at {:?}", path);
+                    }
+                    seen.push(path);
+                    ctx = &contexts[path];
+                },
+                None => break,
+            }
+        }
+        Heritage { root: ctx, blocks }
+    }
+}
+
+pub type Contexts<'a> = HashMap<&'a PathBuf, Context<'a>>;
+
+type BlockAncestry<'a> = HashMap<&'a str, Vec<(&'a Context<'a>, &'a Node<'a>)>>;
@@ -0,0 +1,374 @@
+// Tracks the HTML context of the output written by a template, so that each
+// expression can be escaped according to where it ends up: in text, in an
+// attribute value, in a URL, or in script or style code.
+//
+// The lexer only sees the literal parts of a template. It follows the HTML
+// tokenizer closely enough to recognize tags, attributes, comments and the
+// contents of `<script>` and `<style>` elements, but does not try to be a
+// complete implementation.
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Element {
+    Normal,
+    Script,
+    Style,
+}
+
+impl Element {
+    fn from_name(name: &str) -> Element {
+        match name {
+            "script" => Element::Script,
+            "style" => Element::Style,
+            _ => Element::Normal,
+        }
+    }
+
+    // The state after the start tag of this element is closed.
+    fn content(self) -> State {
+        match self {
+            Element::Normal => State::Text,
+            Element::Script => State::Script(Js::Code),
+            Element::Style => State::Style,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Quote {
+    Double,
+    Single,
+    None,
+}
+
+// Where in a URL attribute value the output currently is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Url {
+    Start,
+    Path,
+    Query,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Js {
+    Code,
+    Slash,
+    Str(char),
+    StrEscape(char),
+    LineComment,
+    BlockComment(bool),
+}
+
+impl Js {
+    fn next(self, c: char) -> Js {
+        match (self, c) {
+            (Js::Code, '"') | (Js::Code, '\'') | (Js::Code, '`') => Js::Str(c),
+            (Js::Code, '/') => Js::Slash,
+            (Js::Code, _) => Js::Code,
+            (Js::Slash, '/') => Js::LineComment,
+            (Js::Slash, '*') => Js::BlockComment(false),
+            (Js::Slash, _) => Js::Code.next(c),
+            (Js::Str(q), '\\') => Js::StrEscape(q),
+            (Js::Str(q), c) if c == q => Js::Code,
+            (Js::Str(q), '\n') if q != '`' => Js::Code,
+            (Js::Str(q), _) => Js::Str(q),
+            (Js::StrEscape(q), _) => Js::Str(q),
+            (Js::LineComment, '\n') => Js::Code,
+            (Js::LineComment, _) => Js::LineComment,
+            (Js::BlockComment(true), '/') => Js::Code,
+            (Js::BlockComment(_), '*') => Js::BlockComment(true),
+            (Js::BlockComment(_), _) => Js::BlockComment(false),
+        }
+    }
+
+    fn escaper(self) -> Result<&'static str, &'static str> {
+        match self {
+            Js::Str('`') => Err("in a JavaScript template literal"),
+            Js::Str(_) | Js::StrEscape(_) => Ok("::askama::escaping::Js"),
+            Js::LineComment | Js::BlockComment(_) => Err("in a JavaScript comment"),
+            Js::Code | Js::Slash => Err("in JavaScript code outside of a string literal"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Attr {
+    Normal,
+    Url(Url),
+    Js(Js),
+    Css,
+}
+
+impl Attr {
+    fn from_name(name: &str) -> Attr {
+        match name {
+            "href" | "src" | "action" | "formaction" | "cite" | "poster" | "background" |
+            "longdesc" | "usemap" | "codebase" | "data" | "manifest" | "srcset" => {
+                Attr::Url(Url::Start)
+            },
+            "style" => Attr::Css,
+            _ if name.starts_with("on") => Attr::Js(Js::Code),
+            _ => Attr::Normal,
+        }
+    }
+
+    fn next(self, c: char) -> Attr {
+        match self {
+            Attr::Url(_) if c == '?' || c == '#' => Attr::Url(Url::Query),
+            // Browsers strip leading whitespace and control characters from
+            // URLs, so the scheme still has to be checked after them.
+            Attr::Url(Url::Start) if c.is_ascii_whitespace() || c.is_control() => self,
+            Attr::Url(Url::Start) => Attr::Url(Url::Path),
+            Attr::Js(js) => Attr::Js(js.next(c)),
+            attr => attr,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum State {
+    Text,
+    TagOpen,
+    TagName(String),
+    EndTag,
+    MarkupDecl(String),
+    Comment(u8),
+    InTag(Element),
+    AttrName(Element, String),
+    AfterAttrName(Element, Attr),
+    BeforeAttrValue(Element, Attr),
+    AttrValue(Element, Attr, Quote),
+    Script(Js),
+    Style,
+}
+
+impl State {
+    // States that only differ in a partially read tag or attribute name are
+    // considered equal, so that attributes can be written conditionally.
+    fn normalize(self) -> State {
+        match self {
+            State::TagName(name) => State::InTag(Element::from_name(&name)),
+            State::AttrName(elem, _) | State::AfterAttrName(elem, _) => State::InTag(elem),
+            state => state,
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HtmlContext {
+    state: State,
+}
+
+impl HtmlContext {
+    pub fn new() -> HtmlContext {
+        HtmlContext { state: State::Text }
+    }
+
+    // Advances the context over literal output.
+    pub fn feed(&mut self, s: &str) {
+        for (i, c) in s.char_indices() {
+            self.state = match self.state.clone() {
+                State::Script(_) if ends_element(&s[i..], "script") => State::EndTag,
+                State::Style if ends_element(&s[i..], "style") => State::EndTag,
+                state => next(state, c),
+            };
+        }
+    }
+
+    // Advances the context over the output of an expression.
+    pub fn feed_expr(&mut self) {
+        match self.state {
+            State::AttrValue(elem, Attr::Url(Url::Start), quote) => {
+                self.state = State::AttrValue(elem, Attr::Url(Url::Path), quote);
+            },
+            State::TagOpen => self.state = State::TagName(String::new()),
+            _ => {},
+        }
+    }
+
+    // Returns the path of the escaper to use for an expression written in
+    // the current context, or a description of the context if no escaper
+    // can make the output safe there.
+    pub fn escaper(&self) -> Result<&'static str, &'static str> {
+        match self.state {
+            State::Text | State::Comment(_) => Ok("::askama::escaping::Html"),
+            State::TagOpen | State::TagName(_) | State::EndTag => Err("in a tag name"),
+            State::AttrValue(_, _, Quote::None) | State::BeforeAttrValue(_, _) => {
+                Err("in an unquoted attribute value")
+            },
+            State::AttrValue(_, attr, _) => match attr {
+                Attr::Normal => Ok("::askama::escaping::Html"),
+                Attr::Url(Url::Start) => Ok("::askama::escaping::HtmlUrl"),
+                Attr::Url(Url::Path) => Ok("::askama::escaping::HtmlUrlPath"),
+                Attr::Url(Url::Query) => Ok("::askama::escaping::Url"),
+                Attr::Js(js) => js.escaper(),
+                Attr::Css => Ok("::askama::escaping::Css"),
+            },
+            State::Script(js) => js.escaper(),
+            State::Style => Ok("::askama::escaping::Css"),
+            State::MarkupDecl(_) => Err("in a markup declaration"),
+            _ => Err("inside a tag"),
+        }
+    }
+
+    // Combines the contexts at the end of alternative branches, which must
+    // all end up in the same context.
+    pub fn merge(branches: Vec<HtmlContext>) -> Result<HtmlContext, ()> {
+        if branches.windows(2).all(|pair| pair[0] == pair[1]) {
+            return Ok(branches.into_iter().next().unwrap_or_else(HtmlContext::new));
+        }
+        let mut states = branches.into_iter().map(|ctx| ctx.state.normalize());
+        let first = states.next().unwrap_or(State::Text);
+        for state in states {
+            if state != first {
+                return Err(());
+            }
+        }
+        Ok(HtmlContext { state: first })
+    }
+}
+
+fn ends_element(s: &str, name: &str) -> bool {
+    let end = name.len() + 2;
+    s.len() >= end && s.is_char_boundary(end) && s[..end].eq_ignore_ascii_case(&format!("</{}", name))
+}
+
+fn next(state: State, c: char) -> State {
+    match state {
+        State::Text => match c {
+            '<' => State::TagOpen,
+            _ => State::Text,
+        },
+        State::TagOpen => match c {
+            '/' => State::EndTag,
+            '!' => State::MarkupDecl(String::new()),
+            c if c.is_ascii_alphabetic() => State::TagName(c.to_ascii_lowercase().to_string()),
+            _ => State::Text,
+        },
+        State::TagName(mut name) => match c {
+            '>' => Element::from_name(&name).content(),
+            '/' => State::InTag(Element::from_name(&name)),
+            c if c.is_whitespace() => State::InTag(Element::from_name(&name)),
+            c => {
+                name.push(c.to_ascii_lowercase());
+                State::TagName(name)
+            },
+        },
+        State::EndTag => match c {
+            '>' => State::Text,
+            _ => State::EndTag,
+        },
+        State::MarkupDecl(mut seen) => {
+            if c == '>' {
+                return State::Text;
+            }
+            if seen.len() < 2 {
+                seen.push(c);
+                if seen == "--" {
+                    return State::Comment(0);
+                }
+            }
+            State::MarkupDecl(seen)
+        },
+        State::Comment(dashes) => match c {
+            '>' if dashes >= 2 => State::Text,
+            '-' => State::Comment(dashes + 1),
+            _ => State::Comment(0),
+        },
+        State::InTag(elem) => match c {
+            '>' => elem.content(),
+            '/' => State::InTag(elem),
+            c if c.is_whitespace() => State::InTag(elem),
+            c => State::AttrName(elem, c.to_ascii_lowercase().to_string()),
+        },
+        State::AttrName(elem, mut name) => match c {
+            '>' => elem.content(),
+            '/' => State::InTag(elem),
+            '=' => State::BeforeAttrValue(elem, Attr::from_name(&name)),
+            c if c.is_whitespace() => State::AfterAttrName(elem, Attr::from_name(&name)),
+            c => {
+                name.push(c.to_ascii_lowercase());
+                State::AttrName(elem, name)
+            },
+        },
+        State::AfterAttrName(elem, attr) => match c {
+            '>' => elem.content(),
+            '/' => State::InTag(elem),
+            '=' => State::BeforeAttrValue(elem, attr),
+            c if c.is_whitespace() => State::AfterAttrName(elem, attr),
+            c => State::AttrName(elem, c.to_ascii_lowercase().to_string()),
+        },
+        State::BeforeAttrValue(elem, attr) => match c {
+            '>' => elem.content(),
+            '"' => State::AttrValue(elem, attr, Quote::Double),
+            '\'' => State::AttrValue(elem, attr, Quote::Single),
+            c if c.is_whitespace() => State::BeforeAttrValue(elem, attr),
+            c => State::AttrValue(elem, attr.next(c), Quote::None),
+        },
+        State::AttrValue(elem, attr, quote) => match (quote, c) {
+            (Quote::Double, '"') | (Quote::Single, '\'') => State::InTag(elem),
+            (Quote::None, '>') => elem.content(),
+            (Quote::None, c) if c.is_whitespace() => State::InTag(elem),
+            (quote, c) => State::AttrValue(elem, attr.next(c), quote),
+        },
+        State::Script(js) => State::Script(js.next(c)),
+        State::Style => State::Style,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::HtmlContext;
+
+    fn escaper(s: &str) -> Result<&'static str, &'static str> {
+        let mut ctx = HtmlContext::new();
+        ctx.feed(s);
+        ctx.escaper()
+    }
+
+    #[test]
+    fn test_contexts() {
+        assert_eq!(escaper("<p>"), Ok("::askama::escaping::Html"));
+        assert_eq!(escaper("<!-- "), Ok("::askama::escaping::Html"));
+        assert_eq!(escaper("<a title='"), Ok("::askama::escaping::Html"));
+        assert_eq!(escaper("<a href=\""), Ok("::askama::escaping::HtmlUrl"));
+        assert_eq!(escaper("<a href=\"/u/"), Ok("::askama::escaping::HtmlUrlPath"));
+        assert_eq!(escaper("<a href=\" \t"), Ok("::askama::escaping::HtmlUrl"));
+        assert_eq!(escaper("<a href=\"/u?q="), Ok("::askama::escaping::Url"));
+        assert_eq!(escaper("<b onclick=\"f('"), Ok("::askama::escaping::Js"));
+        assert_eq!(escaper("<script>var s = \"a\\\""), Ok("::askama::escaping::Js"));
+        assert_eq!(escaper("<style>p { color: "), Ok("::askama::escaping::Css"));
+        assert_eq!(escaper("<p style=\"color: "), Ok("::askama::escaping::Css"));
+        assert_eq!(escaper("<script>var s = 'a' + </script>"), Ok("::askama::escaping::Html"));
+    }
+
+    #[test]
+    fn test_unsafe_contexts() {
+        assert!(escaper("<a title=").is_err());
+        assert!(escaper("<a title=x").is_err());
+        assert!(escaper("<a ").is_err());
+        assert!(escaper("<script>var x = ").is_err());
+        assert!(escaper("<b onclick=\"f(").is_err());
+        assert!(escaper("<script>// ").is_err());
+        assert!(escaper("<").is_err());
+        assert!(escaper("<h").is_err());
+        assert!(escaper("</h").is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut tag = HtmlContext::new();
+        tag.feed("<li");
+        let mut attr = tag.clone();
+        attr.feed(" class=\"x\"");
+        let mut merged = HtmlContext::merge(vec![attr, tag.clone()]).unwrap();
+        merged.feed(">");
+        assert_eq!(merged, HtmlContext::new());
+
+        let mut text = tag.clone();
+        text.feed(">");
+        assert!(HtmlContext::merge(vec![tag, text]).is_err());
+    }
+}
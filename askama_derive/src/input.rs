@@ -1,3 +1,4 @@
+use shared::config::{self, Config, Syntax};
 use shared::path;
 
 use std::path::{Path, PathBuf};
@@ -7,29 +8,39 @@ use syn;
 
 pub struct TemplateInput<'a> {
     pub ast: &'a syn::DeriveInput,
+    pub config: &'a Config,
+    pub syntax: &'a Syntax,
     pub meta: TemplateMeta,
     pub path: PathBuf,
     pub source: String,
 }
 
 impl<'a> TemplateInput<'a> {
-    pub fn new(ast: &'a syn::DeriveInput) -> TemplateInput<'a> {
-        let meta = TemplateMeta::new(ast);
+    pub fn new(ast: &'a syn::DeriveInput, config: &'a Config) -> TemplateInput<'a> {
+        let meta = TemplateMeta::new(ast, config);
         let (path, source) = match meta.source {
             Source::Source(ref s) => {
-                let path = match meta.ext {
-                    Some(ref v) => PathBuf::from(format!("_.{}", v)),
-                    None => PathBuf::new(),
-                };
+                // Templates given inline are treated as if they were in the
+                // first configured template directory, for finding related
+                // templates
+                let path = config.inline_dir.join(format!("_.{}", meta.ext.as_ref().unwrap()));
                 (path, s.clone())
             },
             Source::Path(ref s) => {
-                let path = path::find_template_from_path(s, None);
+                let path = if meta.in_out_dir {
+                    path::find_template_in_out_dir(s)
+                } else {
+                    path::find_template_from_path(s, None, config)
+                };
                 let src = path::get_template_source(&path);
                 (path, src)
             },
         };
-        TemplateInput { ast, meta, path, source }
+        let syntax = match meta.syntax {
+            Some(ref name) => config.syntax(Some(name)),
+            None => config.syntax_for(&path),
+        };
+        TemplateInput { ast, config, syntax, meta, path, source }
     }
 }
 
@@ -39,10 +50,13 @@ pub struct TemplateMeta {
     pub print: Print,
     pub escaping: EscapeMode,
     pub ext: Option<String>,
+    pub syntax: Option<String>,
+    pub suppress_whitespace: bool,
+    pub in_out_dir: bool,
 }
 
 impl TemplateMeta {
-    fn new(ast: &syn::DeriveInput) -> TemplateMeta {
+    fn new(ast: &syn::DeriveInput, config: &Config) -> TemplateMeta {
         let attr = ast.attrs
             .iter()
             .find(|a| a.interpret_meta().unwrap().name() == "template");
@@ -52,9 +66,12 @@ impl TemplateMeta {
 
         let attr = attr.unwrap();
         let mut source = None;
-        let mut print = Print::None;
+        let mut print = config.print.clone().into();
         let mut escaping = None;
         let mut ext = None;
+        let mut syntax = None;
+        let mut suppress_whitespace = config.suppress_whitespace;
+        let mut in_out_dir = false;
         if let syn::Meta::List(ref inner) = attr.interpret_meta().unwrap() {
             for nm_item in inner.nested.iter() {
                 if let syn::NestedMeta::Meta(ref item) = *nm_item {
@@ -91,14 +108,38 @@ impl TemplateMeta {
                             } else {
                                 panic!("ext value must be string literal");
                             },
+                            "syntax" => if let syn::Lit::Str(ref s) = pair.lit {
+                                syntax = Some(s.value());
+                            } else {
+                                panic!("syntax value must be string literal");
+                            },
+                            "whitespace" => if let syn::Lit::Str(ref s) = pair.lit {
+                                suppress_whitespace = match s.value().as_ref() {
+                                    "preserve" => false,
+                                    "suppress" => true,
+                                    v => panic!("invalid value for whitespace option: {}", v),
+                                };
+                            } else {
+                                panic!("whitespace value must be string literal");
+                            },
                             attr => panic!("unsupported annotation key '{}' found", attr),
                         }
+                    } else if let syn::Meta::Word(ref ident) = *item {
+                        match ident.to_string().as_ref() {
+                            "in_out_dir" => in_out_dir = true,
+                            attr => panic!("unsupported annotation '{}' found", attr),
+                        }
                     }
                 }
             }
         }
 
         let source = source.expect("template path or source not found in attributes");
+        if in_out_dir {
+            if let Source::Source(_) = source {
+                panic!("'in_out_dir' attribute cannot be used with 'source' attribute");
+            }
+        }
         match (&source, ext.is_some()) {
             (&Source::Path(_), true) => {
                 panic!("'ext' attribute cannot be used with 'path' attribute")
@@ -116,14 +157,13 @@ impl TemplateMeta {
                         Path::new(p).extension().map(|s| s.to_str().unwrap()).unwrap_or(""),
                     Source::Source(_) => ext.as_ref().unwrap(), // Already panicked if None
                 };
-                if HTML_EXTENSIONS.contains(&ext) {
-                    EscapeMode::Html
-                } else {
-                    EscapeMode::None
+                match config.escaper(ext) {
+                    Some(mode) => mode.to_owned().into(),
+                    None => EscapeMode::None,
                 }
             },
         };
-        TemplateMeta { source, print, escaping, ext }
+        TemplateMeta { source, print, escaping, ext, syntax, suppress_whitespace, in_out_dir }
     }
 }
 
@@ -132,10 +172,36 @@ enum Source {
     Source(String),
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum EscapeMode {
     Html,
     None,
+    Js,
+    Css,
+    Url,
+    Xml,
+    Latex,
+    Shell,
+    Custom(String),
+}
+
+impl EscapeMode {
+    // The path to the `Escaper` implementation for this mode, as used in
+    // generated code.
+    pub fn escaper(&self) -> &str {
+        use self::EscapeMode::*;
+        match *self {
+            Html => "::askama::escaping::Html",
+            None => "::askama::escaping::Text",
+            Js => "::askama::escaping::Js",
+            Css => "::askama::escaping::Css",
+            Url => "::askama::escaping::Url",
+            Xml => "::askama::escaping::Xml",
+            Latex => "::askama::escaping::Latex",
+            Shell => "::askama::escaping::Shell",
+            Custom(ref path) => path,
+        }
+    }
 }
 
 impl From<String> for EscapeMode {
@@ -144,6 +210,13 @@ impl From<String> for EscapeMode {
         match s.as_ref() {
             "html" => Html,
             "none" => None,
+            "js" => Js,
+            "css" => Css,
+            "url" => Url,
+            "xml" => Xml,
+            "latex" => Latex,
+            "shell" => Shell,
+            v if config::is_escape_mode(v) => Custom(s),
             v => panic!("invalid value for escape option: {}", v),
         }
     }
@@ -169,5 +242,3 @@ impl From<String> for Print {
         }
     }
 }
-
-const HTML_EXTENSIONS: [&str; 3] = ["html", "htm", "xml"];
@@ -6,19 +6,22 @@ extern crate proc_macro2;
 #[macro_use]
 extern crate quote;
 extern crate syn;
+extern crate unicode_xid;
 
 mod input;
 mod generator;
+mod heritage;
+mod html;
 mod parser;
 
-use input::Print;
-use parser::{Macro, Node};
+use heritage::Context;
+use input::{Print, TemplateInput};
 use proc_macro::TokenStream;
+use shared::config::{self, Config, Syntax};
 use shared::path;
 
-use std::borrow::Cow;
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[proc_macro_derive(Template, attributes(template))]
 pub fn derive_template(input: TokenStream) -> TokenStream {
@@ -27,59 +30,157 @@ pub fn derive_template(input: TokenStream) -> TokenStream {
         syn::Data::Struct(ref data) => data,
         _ => panic!("#[derive(Template)] can only be used with structs"),
     };
-    build_template(&ast).parse().unwrap()
+    build_template(&ast, config()).parse().unwrap()
+}
+
+// Returns the configuration from `askama.toml`, which is read and validated
+// once per compilation.
+fn config() -> &'static Config {
+    thread_local! {
+        static CONFIG: &'static Config = {
+            Box::leak(Box::new(Config::new(&config::read_config_file())))
+        };
+    }
+    CONFIG.with(|config| *config)
 }
 
 /// Takes a `syn::DeriveInput` and generates source code for it
 ///
 /// Reads the metadata from the `template()` attribute to get the template
 /// metadata, then fetches the source from the filesystem. The source is
-/// parsed, and the parse tree is fed to the code generator. Will print
-/// the parse tree and/or generated source according to the `print` key's
-/// value as passed to the `template()` attribute.
-fn build_template(ast: &syn::DeriveInput) -> String {
-    let data = input::TemplateInput::new(ast);
-    let nodes = parser::parse(data.source.as_ref());
-    let imports = Imports::new(&nodes, &data.path);
-    if data.meta.print == Print::Ast || data.meta.print == Print::All {
-        println!("{:?}", nodes);
-    }
-    let code = generator::generate(&data, &nodes, &imports.macro_map());
-    if data.meta.print == Print::Code || data.meta.print == Print::All {
+/// parsed, along with the sources of all templates it extends, includes or
+/// imports, and the parse trees are fed to the code generator. Will print the
+/// parse tree and/or generated source according to the `print` key's value as
+/// passed to the `template()` attribute, or as configured in `askama.toml`.
+fn build_template(ast: &syn::DeriveInput, config: &Config) -> String {
+    let input = TemplateInput::new(ast, config);
+    let mut sources = HashMap::new();
+    find_used_templates(&mut sources, &input);
+
+    let mut parsed = HashMap::new();
+    for (path, src) in &sources {
+        parsed.insert(path, parser::parse(src, template_syntax(&input, path)));
+    }
+
+    let mut contexts = HashMap::new();
+    for (path, nodes) in &parsed {
+        contexts.insert(*path, Context::new(path, nodes, config));
+    }
+
+    if input.meta.print == Print::Ast || input.meta.print == Print::All {
+        println!("{:?}", parsed[&input.path]);
+    }
+    let code = generator::generate(&input, &contexts);
+    if input.meta.print == Print::Code || input.meta.print == Print::All {
         println!("{}", code);
     }
     code
 }
 
-struct Imports<'a> {
-    sources: HashMap<&'a str, Cow<'a, str>>,
+// The syntax of a template: the deriving template uses the syntax set in its
+// attribute, if any; others use the syntax configured for their extension.
+fn template_syntax<'a>(input: &TemplateInput<'a>, path: &Path) -> &'a Syntax {
+    if path == input.path {
+        input.syntax
+    } else {
+        input.config.syntax_for(path)
+    }
 }
 
-impl<'a> Imports<'a> {
-    fn new(parent_nodes: &'a [Node], parent_path: &'a Path) -> Imports<'a> {
-        let sources = parent_nodes.iter().filter_map(|n| {
-            match *n {
-                Node::Import(_, import_path, scope) => {
-                    let path = path::find_template_from_path(import_path, Some(parent_path));
-                    let src = path::get_template_source(&path);
-                    Some((scope, Cow::Owned(src)))
-                },
-                _ => None,
-            }
-        }).collect();
-        Imports { sources }
-    }
-
-    fn macro_map(&'a self) -> HashMap<(&'a str, &'a str), Macro<'a>> {
-        let mut macro_map = HashMap::new();
-        for (scope, s) in &self.sources {
-            for n in parser::parse(s.as_ref()) {
-                match n {
-                    Node::Macro(name, m) => macro_map.insert((*scope, name), m),
-                    _ => None,
-                };
+// Collects the sources of the given template and of all templates it depends
+// on through `extends`, `include` and `import` tags, keyed by their path.
+fn find_used_templates(map: &mut HashMap<PathBuf, String>, input: &TemplateInput) {
+    let mut check = vec![(input.path.clone(), input.source.clone())];
+    while let Some((path, source)) = check.pop() {
+        if map.contains_key(&path) {
+            continue;
+        }
+        {
+            let nodes = parser::parse(&source, template_syntax(input, &path));
+            for dependency in Context::new(&path, &nodes, input.config).dependencies() {
+                let source = path::get_template_source(dependency);
+                check.push((dependency.clone(), source));
             }
         }
-        macro_map
+        map.insert(path, source);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{build_template, Config};
+    use syn;
+
+    fn build(source: &str) -> String {
+        let ast: syn::DeriveInput = syn::parse_str(&format!(
+            "#[template(source = {:?}, ext = \"html\")] struct T {{ s: String }}", source
+        )).unwrap();
+        build_template(&ast, &Config::new(""))
+    }
+
+    fn build_with_templates(source: &str) -> String {
+        let ast: syn::DeriveInput = syn::parse_str(&format!(
+            "#[template(source = {:?}, ext = \"html\")] struct T {{ title: String }}", source
+        )).unwrap();
+        build_template(&ast, &Config::new("[general]\ndirs = [\"../testing/templates\"]"))
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot safely escape expression in a tag name")]
+    fn test_tag_name() {
+        build("<{{ s }}>text</{{ s }}>");
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot safely escape expression in a tag name")]
+    fn test_end_tag_name() {
+        build("<b>text</{{ s }}>");
+    }
+
+    #[test]
+    fn test_safe_tag_name() {
+        build("<{{ s|safe }}>text</{{ s|safe }}>");
+    }
+
+    #[test]
+    fn test_url_leading_whitespace() {
+        assert!(build("<a href=\" {{ s }}\">").contains("::askama::escaping::HtmlUrl)"));
+    }
+
+    #[test]
+    fn test_conditional_attribute() {
+        build("<li{% if s.is_empty() %} class=\"x\"{% endif %}>{{ s }}</li>");
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot safely escape expression in an unquoted attribute value")]
+    fn test_unquoted_attribute() {
+        build("<a title={{ s }}>");
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot safely escape expression in an unquoted attribute value")]
+    fn test_escape_filter_context() {
+        build("<a title={{ s|e }}>");
+    }
+
+    #[test]
+    #[should_panic(expected = "template 'T' does not override required block 'title'")]
+    fn test_missing_required_block() {
+        build_with_templates("{% extends \"required-base.html\" %}");
+    }
+
+    #[test]
+    #[should_panic(expected = "template 'T' does not override required block 'content'")]
+    fn test_missing_required_block_from_middle() {
+        build_with_templates("{% extends \"required-mid.html\" %}");
+    }
+
+    #[test]
+    fn test_required_block_from_middle() {
+        build_with_templates(
+            "{% extends \"required-mid.html\" %}{% block content %}{{ title }}{% endblock %}"
+        );
     }
 }
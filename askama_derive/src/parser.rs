@@ -2,7 +2,9 @@
 #![cfg_attr(rustfmt, rustfmt_skip)]
 
 use nom::{self, IResult};
+use shared::config::Syntax;
 use std::str;
+use unicode_xid::UnicodeXID;
 
 #[derive(Debug)]
 pub enum Expr<'a> {
@@ -17,6 +19,9 @@ pub enum Expr<'a> {
     BinOp(&'a str, Box<Expr<'a>>, Box<Expr<'a>>),
     Group(Box<Expr<'a>>),
     MethodCall(Box<Expr<'a>>, &'a str, Vec<Expr<'a>>),
+    OptAttr(Box<Expr<'a>>, &'a str),
+    OptMethodCall(Box<Expr<'a>>, &'a str, Vec<Expr<'a>>),
+    Try(Box<Expr<'a>>),
 }
 
 #[derive(Debug)]
@@ -39,9 +44,30 @@ pub enum Target<'a> {
     Name(&'a str),
 }
 
+#[derive(Debug)]
+pub struct CondTest<'a> {
+    pub target: Option<(MatchVariant<'a>, Vec<MatchParameter<'a>>)>,
+    pub expr: Expr<'a>,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct WS(pub bool, pub bool);
 
+#[derive(Debug)]
+pub struct Include<'a> {
+    pub path: &'a str,
+    pub ignore_missing: bool,
+    pub indent: usize,
+    pub vars: Option<Vec<(&'a str, Expr<'a>)>>,
+}
+
+#[derive(Debug)]
+pub struct Component<'a> {
+    pub path: Vec<&'a str>,
+    pub fields: Vec<(&'a str, Expr<'a>)>,
+    pub nodes: Vec<Node<'a>>,
+}
+
 #[derive(Debug)]
 pub struct Macro<'a> {
     pub ws1: WS,
@@ -56,19 +82,28 @@ pub enum Node<'a> {
     Comment(WS),
     Expr(WS, Expr<'a>),
     Call(WS, Option<& 'a str>, &'a str, Vec<Expr<'a>>),
-    LetDecl(WS, Target<'a>),
-    Let(WS, Target<'a>, Expr<'a>),
-    Cond(Vec<(WS, Option<Expr<'a>>, Vec<Node<'a>>)>, WS),
+    LetDecl(WS, bool, Target<'a>),
+    Let(WS, bool, Target<'a>, Expr<'a>),
+    Set(WS, Target<'a>, Expr<'a>),
+    LetElse(WS, CondTest<'a>, Vec<Node<'a>>, WS),
+    With(WS, Vec<(bool, &'a str, Expr<'a>)>, Vec<Node<'a>>, WS),
+    Cond(Vec<Cond<'a>>, WS),
     Match(WS, Expr<'a>, Option<&'a str>, Vec<When<'a>>, WS),
     Loop(WS, Target<'a>, Expr<'a>, Vec<Node<'a>>, WS),
     Extends(Expr<'a>),
-    BlockDef(WS, &'a str, Vec<Node<'a>>, WS),
-    Include(WS, &'a str),
+    BlockDef(WS, &'a str, bool, Vec<Node<'a>>, WS),
+    Super(WS),
+    Include(WS, Include<'a>),
+    Component(WS, Component<'a>, WS),
+    Push(WS, &'a str, Vec<Node<'a>>, WS),
+    Stack(WS, &'a str),
+    Once(WS, Vec<Node<'a>>, WS),
+    AutoEscape(WS, &'a str, Vec<Node<'a>>, WS),
     Import(WS, &'a str, &'a str),
     Macro(&'a str, Macro<'a>),
 }
 
-pub type Cond<'a> = (WS, Option<Expr<'a>>, Vec<Node<'a>>);
+pub type Cond<'a> = (WS, Option<CondTest<'a>>, Vec<Node<'a>>);
 pub type When<'a> = (WS, Option<MatchVariant<'a>>, Vec<MatchParameter<'a>>, Vec<Node<'a>>);
 
 fn split_ws_parts(s: &[u8]) -> Node {
@@ -97,49 +132,48 @@ fn split_ws_parts(s: &[u8]) -> Node {
               str::from_utf8(res.2).unwrap())
 }
 
-enum ContentState {
-    Any,
-    Brace(usize),
-    End(usize),
-}
-
-fn take_content(i: &[u8]) -> IResult<&[u8], Node> {
-    use parser::ContentState::*;
-    let mut state = Any;
-    for (idx, c) in i.iter().enumerate() {
-        state = match (state, *c) {
-            (Any, b'{') => Brace(idx),
-            (Brace(start), b'{') |
-            (Brace(start), b'%') |
-            (Brace(start), b'#') => End(start),
-            (Any, _) |
-            (Brace(_), _) => Any,
-            (End(_), _) => panic!("cannot happen"),
-        };
-        if let End(_) = state {
-            break;
-        }
-    }
-    match state {
-        Any |
-        Brace(_) => IResult::Done(&i[..0], split_ws_parts(i)),
-        End(0) => IResult::Error(nom::ErrorKind::Custom(0)),
-        End(start) => IResult::Done(&i[start..], split_ws_parts(&i[..start])),
+// Takes literal content up to the first start delimiter of the syntax.
+fn take_content<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    let starts = [&s.block_start, &s.expr_start, &s.comment_start];
+    let end = (0..i.len()).find(|&idx| {
+        starts.iter().any(|start| i[idx..].starts_with(start.as_bytes()))
+    });
+    match end {
+        None => IResult::Done(&i[..0], split_ws_parts(i)),
+        Some(0) => IResult::Error(nom::ErrorKind::Custom(0)),
+        Some(end) => IResult::Done(&i[end..], split_ws_parts(&i[..end])),
     }
 }
 
+// Decodes the first character of a UTF-8 encoded byte slice.
+fn next_char(input: &[u8]) -> Option<(char, usize)> {
+    let len = match input.first() {
+        None => return None,
+        Some(&b) if b < 0x80 => 1,
+        Some(&b) if b >= 0xf0 => 4,
+        Some(&b) if b >= 0xe0 => 3,
+        Some(_) => 2,
+    };
+    input.get(..len)
+        .and_then(|s| str::from_utf8(s).ok())
+        .and_then(|s| s.chars().next())
+        .map(|c| (c, len))
+}
+
 fn identifier(input: &[u8]) -> IResult<&[u8], &str> {
-    if !nom::is_alphabetic(input[0]) && input[0] != b'_' {
-        return IResult::Error(nom::ErrorKind::Custom(0));
+    let start = if input.starts_with(b"r#") { 2 } else { 0 };
+    match next_char(&input[start..]) {
+        Some((c, _)) if c == '_' || UnicodeXID::is_xid_start(c) => {},
+        _ => return IResult::Error(nom::ErrorKind::Custom(0)),
     }
-    for (i, ch) in input.iter().enumerate() {
-        if i == 0 || nom::is_alphanumeric(*ch) || *ch == b'_' {
-            continue;
+    let mut end = start;
+    while let Some((c, len)) = next_char(&input[end..]) {
+        if end > start && !UnicodeXID::is_xid_continue(c) {
+            break;
         }
-        return IResult::Done(&input[i..],
-                             str::from_utf8(&input[..i]).unwrap());
+        end += len;
     }
-    IResult::Done(&input[1..], str::from_utf8(&input[..1]).unwrap())
+    IResult::Done(&input[end..], str::from_utf8(&input[..end]).unwrap())
 }
 
 named!(num_lit<&str>, map!(nom::digit,
@@ -273,6 +307,11 @@ named!(parameters<Vec<&'a str>>, do_parse!(
 
 named!(with_parameters<Vec<MatchParameter>>, do_parse!(
     tag_s!("with") >>
+    params: match_parameters >>
+    (params)
+));
+
+named!(match_parameters<Vec<MatchParameter>>, do_parse!(
     ws!(tag_s!("(")) >>
     vals: opt!(do_parse!(
         arg0: ws!(match_parameter) >>
@@ -318,11 +357,20 @@ named!(match_parameter<MatchParameter>, alt!(
     param_str_lit
 ));
 
-named!(attr<(&str, Option<Vec<Expr>>)>, do_parse!(
-    tag_s!(".") >>
-    attr: alt!(num_lit | identifier) >>
-    args: opt!(arguments) >>
-    (attr, args)
+enum Suffix<'a> {
+    Attr(bool, &'a str, Option<Vec<Expr<'a>>>),
+    Try,
+}
+
+named!(attr<Suffix>, alt!(
+    do_parse!(
+        optional: opt!(tag_s!("&")) >>
+        tag_s!(".") >>
+        attr: alt!(num_lit | identifier) >>
+        args: opt!(arguments) >>
+        (Suffix::Attr(optional.is_some(), attr, args))
+    ) |
+    map!(terminated!(tag_s!("?"), not!(tag_s!("?"))), |_| Suffix::Try)
 ));
 
 named!(expr_attr<Expr>, do_parse!(
@@ -330,11 +378,14 @@ named!(expr_attr<Expr>, do_parse!(
     attrs: many0!(attr) >>
     ({
         let mut res = obj;
-        for (aname, args) in attrs {
-            res = if args.is_some() {
-                Expr::MethodCall(Box::new(res), aname, args.unwrap())
-            } else {
-                Expr::Attr(Box::new(res), aname)
+        for attr in attrs {
+            let obj = Box::new(res);
+            res = match attr {
+                Suffix::Attr(false, aname, Some(args)) => Expr::MethodCall(obj, aname, args),
+                Suffix::Attr(false, aname, None) => Expr::Attr(obj, aname),
+                Suffix::Attr(true, aname, Some(args)) => Expr::OptMethodCall(obj, aname, args),
+                Suffix::Attr(true, aname, None) => Expr::OptAttr(obj, aname),
+                Suffix::Try => Expr::Try(obj),
             };
         }
         res
@@ -368,7 +419,7 @@ named!(expr_filtered<Expr>, do_parse!(
 ));
 
 named!(expr_unary<Expr>, do_parse!(
-    op: opt!(alt!(tag_s!("!") | tag_s!("-"))) >>
+    op: opt!(alt!(tag_s!("!") | tag_s!("-") | tag_s!("&"))) >>
     expr: expr_filtered >>
     (match op {
         Some(op) => Expr::Unary(str::from_utf8(op).unwrap(), Box::new(expr)),
@@ -392,7 +443,8 @@ macro_rules! expr_prec_layer {
 }
 
 expr_prec_layer!(expr_muldivmod, expr_unary, "*", "/", "%");
-expr_prec_layer!(expr_addsub, expr_muldivmod, "+", "-");
+expr_prec_layer!(expr_concat, expr_muldivmod, "~");
+expr_prec_layer!(expr_addsub, expr_concat, "+", "-");
 expr_prec_layer!(expr_shifts, expr_addsub, ">>", "<<");
 expr_prec_layer!(expr_band, expr_shifts, "&");
 expr_prec_layer!(expr_bxor, expr_band, "^");
@@ -401,16 +453,32 @@ expr_prec_layer!(expr_compare, expr_bor,
     "==", "!=", ">=", ">", "<=", "<"
 );
 expr_prec_layer!(expr_and, expr_compare, "&&");
-expr_prec_layer!(expr_any, expr_and, "||");
+expr_prec_layer!(expr_or, expr_and, "||");
+expr_prec_layer!(expr_any, expr_or, "??");
 
-named!(expr_node<Node>, do_parse!(
-    tag_s!("{{") >>
-    pws: opt!(tag_s!("-")) >>
-    expr: ws!(expr_any) >>
-    nws: opt!(tag_s!("-")) >>
-    tag_s!("}}") >>
-    (Node::Expr(WS(pws.is_some(), nws.is_some()), expr))
-));
+fn expr_node<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        tag_s!(&*s.expr_start) >>
+        pws: opt!(tag_s!("-")) >>
+        expr: ws!(expr_any) >>
+        nws: opt!(tag_s!("-")) >>
+        tag_s!(&*s.expr_end) >>
+        (Node::Expr(WS(pws.is_some(), nws.is_some()), expr))
+    )
+}
+
+fn super_node<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        tag_s!(&*s.expr_start) >>
+        pws: opt!(tag_s!("-")) >>
+        ws!(tag_s!("super")) >>
+        ws!(tag_s!("(")) >>
+        ws!(tag_s!(")")) >>
+        nws: opt!(tag_s!("-")) >>
+        tag_s!(&*s.expr_end) >>
+        (Node::Super(WS(pws.is_some(), nws.is_some())))
+    )
+}
 
 named!(block_call<Node>, do_parse!(
     pws: opt!(tag_s!("-")) >>
@@ -426,106 +494,129 @@ named!(block_call<Node>, do_parse!(
     (Node::Call(WS(pws.is_some(), nws.is_some()), scope, name, args))
 ));
 
-named!(cond_if<Expr>, do_parse!(
+named!(cond_if<CondTest>, do_parse!(
     ws!(tag_s!("if")) >>
-    cond: ws!(expr_any) >>
-    (cond)
+    target: opt!(do_parse!(
+        terminated!(tag_s!("let"), nom::multispace) >>
+        variant: ws!(match_variant) >>
+        params: opt!(match_parameters) >>
+        ws!(tag_s!("=")) >>
+        (variant, params.unwrap_or_default())
+    )) >>
+    expr: ws!(expr_any) >>
+    (CondTest { target, expr })
 ));
 
-named!(cond_block<Cond>, do_parse!(
-    tag_s!("{%") >>
-    pws: opt!(tag_s!("-")) >>
-    ws!(tag_s!("else")) >>
-    cond: opt!(cond_if) >>
-    nws: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    block: parse_template >>
-    (WS(pws.is_some(), nws.is_some()), cond, block)
-));
-
-named!(block_if<Node>, do_parse!(
-    pws1: opt!(tag_s!("-")) >>
-    cond: ws!(cond_if) >>
-    nws1: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    block: parse_template >>
-    elifs: many0!(cond_block) >>
-    tag_s!("{%") >>
-    pws2: opt!(tag_s!("-")) >>
-    ws!(tag_s!("endif")) >>
-    nws2: opt!(tag_s!("-")) >>
-    ({
-       let mut res = Vec::new();
-       res.push((WS(pws1.is_some(), nws1.is_some()), Some(cond), block));
-       res.extend(elifs);
-       Node::Cond(res, WS(pws2.is_some(), nws2.is_some()))
-    })
-));
+fn cond_block<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Cond<'a>> {
+    do_parse!(i,
+        tag_s!(&*s.block_start) >>
+        pws: opt!(tag_s!("-")) >>
+        ws!(tag_s!("else")) >>
+        cond: opt!(cond_if) >>
+        nws: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        block: call!(parse_template, s) >>
+        (WS(pws.is_some(), nws.is_some()), cond, block)
+    )
+}
 
-named!(match_else_block<When>, do_parse!(
-    tag_s!("{%") >>
-    pws: opt!(tag_s!("-")) >>
-    ws!(tag_s!("else")) >>
-    nws: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    block: parse_template >>
-    (WS(pws.is_some(), nws.is_some()), None, vec![], block)
-));
+fn block_if<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        cond: ws!(cond_if) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        block: call!(parse_template, s) >>
+        elifs: many0!(call!(cond_block, s)) >>
+        tag_s!(&*s.block_start) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endif")) >>
+        nws2: opt!(tag_s!("-")) >>
+        ({
+           let mut res = Vec::new();
+           res.push((WS(pws1.is_some(), nws1.is_some()), Some(cond), block));
+           res.extend(elifs);
+           Node::Cond(res, WS(pws2.is_some(), nws2.is_some()))
+        })
+    )
+}
 
-named!(when_block<When>, do_parse!(
-    tag_s!("{%") >>
-    pws: opt!(tag_s!("-")) >>
-    ws!(tag_s!("when")) >>
-    variant: ws!(match_variant) >>
-    params: opt!(ws!(with_parameters)) >>
-    nws: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    block: parse_template >>
-    (WS(pws.is_some(), nws.is_some()), Some(variant), params.unwrap_or_default(), block)
-));
+fn match_else_block<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], When<'a>> {
+    do_parse!(i,
+        tag_s!(&*s.block_start) >>
+        pws: opt!(tag_s!("-")) >>
+        ws!(tag_s!("else")) >>
+        nws: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        block: call!(parse_template, s) >>
+        (WS(pws.is_some(), nws.is_some()), None, vec![], block)
+    )
+}
 
-named!(block_match<Node>, do_parse!(
-    pws1: opt!(tag_s!("-")) >>
-    ws!(tag_s!("match")) >>
-    expr: ws!(expr_any) >>
-    nws1: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    inter: opt!(take_content) >>
-    arms: many1!(when_block) >>
-    else_arm: opt!(match_else_block) >>
-    ws!(tag_s!("{%")) >>
-    pws2: opt!(tag_s!("-")) >>
-    ws!(tag_s!("endmatch")) >>
-    nws2: opt!(tag_s!("-")) >>
-    ({
-        let mut arms = arms;
-        if let Some(arm) = else_arm {
-            arms.push(arm);
-        }
-        let inter = match inter {
-            Some(Node::Lit(lws, val, rws)) => {
-                assert!(val.is_empty(),
-                        "only whitespace allowed between match and first when, found {}", val);
-                assert!(rws.is_empty(),
-                        "only whitespace allowed between match and first when, found {}", rws);
-                Some(lws)
-            },
-            None => None,
-            _ => panic!("only literals allowed between match and first when"),
-        };
-        Node::Match(
-            WS(pws1.is_some(), nws1.is_some()),
-            expr,
-            inter,
-            arms,
-            WS(pws2.is_some(), nws2.is_some()),
-        )
-    })
+fn when_block<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], When<'a>> {
+    do_parse!(i,
+        tag_s!(&*s.block_start) >>
+        pws: opt!(tag_s!("-")) >>
+        ws!(tag_s!("when")) >>
+        variant: ws!(match_variant) >>
+        params: opt!(ws!(with_parameters)) >>
+        nws: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        block: call!(parse_template, s) >>
+        (WS(pws.is_some(), nws.is_some()), Some(variant), params.unwrap_or_default(), block)
+    )
+}
+
+fn block_match<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("match")) >>
+        expr: ws!(expr_any) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        inter: opt!(call!(take_content, s)) >>
+        arms: many1!(call!(when_block, s)) >>
+        else_arm: opt!(call!(match_else_block, s)) >>
+        ws!(tag_s!(&*s.block_start)) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endmatch")) >>
+        nws2: opt!(tag_s!("-")) >>
+        ({
+            let mut arms = arms;
+            if let Some(arm) = else_arm {
+                arms.push(arm);
+            }
+            let inter = match inter {
+                Some(Node::Lit(lws, val, rws)) => {
+                    assert!(val.is_empty(),
+                            "only whitespace allowed between match and first when, found {}", val);
+                    assert!(rws.is_empty(),
+                            "only whitespace allowed between match and first when, found {}", rws);
+                    Some(lws)
+                },
+                None => None,
+                _ => panic!("only literals allowed between match and first when"),
+            };
+            Node::Match(
+                WS(pws1.is_some(), nws1.is_some()),
+                expr,
+                inter,
+                arms,
+                WS(pws2.is_some(), nws2.is_some()),
+            )
+        })
+    )
+}
+
+named!(mutability<bool>, map!(
+    opt!(preceded!(opt!(nom::multispace), terminated!(tag_s!("mut"), nom::multispace))),
+    |m| m.is_some()
 ));
 
 named!(block_let<Node>, do_parse!(
     pws: opt!(tag_s!("-")) >>
     ws!(tag_s!("let")) >>
+    mutable: mutability >>
     var: ws!(target_single) >>
     val: opt!(do_parse!(
         ws!(tag_s!("=")) >>
@@ -534,61 +625,172 @@ named!(block_let<Node>, do_parse!(
     )) >>
     nws: opt!(tag_s!("-")) >>
     (if val.is_some() {
-        Node::Let(WS(pws.is_some(), nws.is_some()), var, val.unwrap())
+        Node::Let(WS(pws.is_some(), nws.is_some()), mutable, var, val.unwrap())
     } else {
-        Node::LetDecl(WS(pws.is_some(), nws.is_some()), var)
+        Node::LetDecl(WS(pws.is_some(), nws.is_some()), mutable, var)
     })
 ));
 
-named!(block_for<Node>, do_parse!(
-    pws1: opt!(tag_s!("-")) >>
-    ws!(tag_s!("for")) >>
+// A `let` with a refutable pattern, of which the `else` body is rendered
+// instead of the rest of the enclosing scope if the pattern does not match.
+fn block_let_else<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("let")) >>
+        variant: ws!(match_variant) >>
+        params: opt!(match_parameters) >>
+        ws!(tag_s!("=")) >>
+        expr: ws!(expr_any) >>
+        ws!(tag_s!("else")) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        block: call!(parse_template, s) >>
+        tag_s!(&*s.block_start) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endlet")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::LetElse(WS(pws1.is_some(), nws1.is_some()),
+                       CondTest { target: Some((variant, params.unwrap_or_default())), expr },
+                       block,
+                       WS(pws2.is_some(), nws2.is_some())))
+    )
+}
+
+named!(block_set<Node>, do_parse!(
+    pws: opt!(tag_s!("-")) >>
+    ws!(tag_s!("set")) >>
     var: ws!(target_single) >>
-    ws!(tag_s!("in")) >>
-    iter: ws!(expr_any) >>
-    nws1: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    block: parse_template >>
-    tag_s!("{%") >>
-    pws2: opt!(tag_s!("-")) >>
-    ws!(tag_s!("endfor")) >>
-    nws2: opt!(tag_s!("-")) >>
-    (Node::Loop(WS(pws1.is_some(), nws1.is_some()),
-                var, iter, block,
-                WS(pws2.is_some(), nws2.is_some())))
+    ws!(tag_s!("=")) >>
+    val: ws!(expr_any) >>
+    nws: opt!(tag_s!("-")) >>
+    (Node::Set(WS(pws.is_some(), nws.is_some()), var, val))
+));
+
+named!(with_var<(bool, &str, Expr)>, do_parse!(
+    mutable: mutability >>
+    name: ws!(identifier) >>
+    tag_s!("=") >>
+    value: ws!(expr_any) >>
+    ((mutable, name, value))
 ));
 
+fn block_with<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("with")) >>
+        var0: with_var >>
+        vars: many0!(do_parse!(
+            tag_s!(",") >>
+            varn: with_var >>
+            (varn)
+        )) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        contents: call!(parse_template, s) >>
+        tag_s!(&*s.block_start) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endwith")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::With(WS(pws1.is_some(), nws1.is_some()), {
+            let mut res = vec![var0];
+            res.extend(vars);
+            res
+        }, contents, WS(pws2.is_some(), nws2.is_some())))
+    )
+}
+
+fn block_for<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("for")) >>
+        var: ws!(target_single) >>
+        ws!(tag_s!("in")) >>
+        iter: ws!(expr_any) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        block: call!(parse_template, s) >>
+        tag_s!(&*s.block_start) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endfor")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::Loop(WS(pws1.is_some(), nws1.is_some()),
+                    var, iter, block,
+                    WS(pws2.is_some(), nws2.is_some())))
+    )
+}
+
 named!(block_extends<Node>, do_parse!(
     ws!(tag_s!("extends")) >>
     name: ws!(expr_str_lit) >>
     (Node::Extends(name))
 ));
 
-named!(block_block<Node>, do_parse!(
-    pws1: opt!(tag_s!("-")) >>
-    ws!(tag_s!("block")) >>
+fn block_block<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("block")) >>
+        name: ws!(identifier) >>
+        required: opt!(ws!(tag_s!("required"))) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        contents: call!(parse_template, s) >>
+        tag_s!(&*s.block_start) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endblock")) >>
+        opt!(ws!(tag_s!(name))) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::BlockDef(WS(pws1.is_some(), nws1.is_some()),
+                        name, required.is_some(), contents,
+                        WS(pws2.is_some(), nws2.is_some())))
+    )
+}
+
+named!(include_var<(&str, Expr)>, do_parse!(
     name: ws!(identifier) >>
-    nws1: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    contents: parse_template >>
-    tag_s!("{%") >>
-    pws2: opt!(tag_s!("-")) >>
-    ws!(tag_s!("endblock")) >>
-    opt!(ws!(tag_s!(name))) >>
-    nws2: opt!(tag_s!("-")) >>
-    (Node::BlockDef(WS(pws1.is_some(), nws1.is_some()),
-                    name, contents,
-                    WS(pws2.is_some(), nws2.is_some())))
+    tag_s!("=") >>
+    value: ws!(expr_any) >>
+    ((name, value))
+));
+
+named!(include_vars<Vec<(&str, Expr)>>, do_parse!(
+    tag_s!("with") >>
+    var0: include_var >>
+    vars: many0!(do_parse!(
+        tag_s!(",") >>
+        varn: include_var >>
+        (varn)
+    )) >>
+    ({
+        let mut res = vec![var0];
+        res.extend(vars);
+        res
+    })
 ));
 
 named!(block_include<Node>, do_parse!(
     pws: opt!(tag_s!("-")) >>
     ws!(tag_s!("include")) >>
     name: ws!(expr_str_lit) >>
+    ignore_missing: opt!(do_parse!(
+        ws!(tag_s!("ignore")) >>
+        ws!(tag_s!("missing")) >>
+        (())
+    )) >>
+    indent: opt!(do_parse!(
+        ws!(tag_s!("indent")) >>
+        width: ws!(num_lit) >>
+        (width)
+    )) >>
+    vars: opt!(ws!(include_vars)) >>
     nws: opt!(tag_s!("-")) >>
-    (Node::Include(WS(pws.is_some(), nws.is_some()), match name {
-        Expr::StrLit(s) => s,
-        _ => panic!("include path must be a string literal"),
+    (Node::Include(WS(pws.is_some(), nws.is_some()), Include {
+        path: match name {
+            Expr::StrLit(s) => s,
+            _ => panic!("include path must be a string literal"),
+        },
+        ignore_missing: ignore_missing.is_some(),
+        indent: indent.map_or(0, |width| width.parse().unwrap()),
+        vars,
     }))
 ));
 
@@ -605,64 +807,220 @@ named!(block_import<Node>, do_parse!(
     }, scope))
 ));
 
-named!(block_macro<Node>, do_parse!(
-    pws1: opt!(tag_s!("-")) >>
-    ws!(tag_s!("macro")) >>
+fn block_macro<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("macro")) >>
+        name: ws!(identifier) >>
+        params: ws!(parameters) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        contents: call!(parse_template, s) >>
+        tag_s!(&*s.block_start) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endmacro")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::Macro(
+             name,
+             Macro {
+                 ws1: WS(pws1.is_some(), nws1.is_some()),
+                 args: params,
+                 nodes: contents,
+                 ws2: WS(pws2.is_some(), nws2.is_some())
+             }
+        ))
+    )
+}
+
+named!(component_path<Vec<&str>>, do_parse!(
+    start: ws!(identifier) >>
+    rest: many0!(do_parse!(
+        tag_s!("::") >>
+        part: ws!(identifier) >>
+        (part)
+    )) >>
+    ({
+        let mut path = vec![start];
+        path.extend(rest);
+        path
+    })
+));
+
+named!(component_field<(&str, Expr)>, do_parse!(
     name: ws!(identifier) >>
-    params: ws!(parameters) >>
-    nws1: opt!(tag_s!("-")) >>
-    tag_s!("%}") >>
-    contents: parse_template >>
-    tag_s!("{%") >>
-    pws2: opt!(tag_s!("-")) >>
-    ws!(tag_s!("endmacro")) >>
-    nws2: opt!(tag_s!("-")) >>
-    (Node::Macro(
-         name,
-         Macro {
-             ws1: WS(pws1.is_some(), nws1.is_some()),
-             args: params,
-             nodes: contents,
-             ws2: WS(pws2.is_some(), nws2.is_some())
-         }
-    ))
+    value: opt!(do_parse!(
+        tag_s!(":") >>
+        value: ws!(expr_any) >>
+        (value)
+    )) >>
+    ((name, value.unwrap_or(Expr::Var(name))))
 ));
 
-named!(block_node<Node>, do_parse!(
-    tag_s!("{%") >>
-    contents: alt!(
-        block_call |
-        block_let |
-        block_if |
-        block_for |
-        block_match |
-        block_extends |
-        block_include |
-        block_import |
-        block_block |
-        block_macro
-    ) >>
-    tag_s!("%}") >>
-    (contents)
-));
-
-named!(block_comment<Node>, do_parse!(
-    tag_s!("{#") >>
+named!(component_fields<Vec<(&str, Expr)>>, do_parse!(
+    tag_s!("{") >>
+    fields: opt!(do_parse!(
+        field0: component_field >>
+        fields: many0!(do_parse!(
+            tag_s!(",") >>
+            fieldn: component_field >>
+            (fieldn)
+        )) >>
+        opt!(ws!(tag_s!(","))) >>
+        ({
+            let mut res = vec![field0];
+            res.extend(fields);
+            res
+        })
+    )) >>
+    ws!(tag_s!("}")) >>
+    (fields.unwrap_or_default())
+));
+
+fn block_component<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("component")) >>
+        path: component_path >>
+        fields: ws!(component_fields) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        contents: call!(parse_template, s) >>
+        tag_s!(&*s.block_start) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endcomponent")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::Component(
+            WS(pws1.is_some(), nws1.is_some()),
+            Component { path, fields, nodes: contents },
+            WS(pws2.is_some(), nws2.is_some()),
+        ))
+    )
+}
+
+fn block_push<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("push")) >>
+        name: ws!(expr_str_lit) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        contents: call!(parse_template, s) >>
+        tag_s!(&*s.block_start) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endpush")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::Push(WS(pws1.is_some(), nws1.is_some()), match name {
+            Expr::StrLit(s) => s,
+            _ => panic!("stack name must be a string literal"),
+        }, contents, WS(pws2.is_some(), nws2.is_some())))
+    )
+}
+
+named!(block_stack<Node>, do_parse!(
     pws: opt!(tag_s!("-")) >>
-    inner: take_until_s!("#}") >>
-    tag_s!("#}") >>
-    (Node::Comment(WS(pws.is_some(), inner.len() > 1 && inner[inner.len() - 1] == b'-')))
+    ws!(tag_s!("stack")) >>
+    name: ws!(expr_str_lit) >>
+    nws: opt!(tag_s!("-")) >>
+    (Node::Stack(WS(pws.is_some(), nws.is_some()), match name {
+        Expr::StrLit(s) => s,
+        _ => panic!("stack name must be a string literal"),
+    }))
 ));
 
-named!(parse_template<Vec<Node<'a>>>, many0!(alt!(
-    take_content |
-    block_comment |
-    expr_node |
-    block_node
-)));
+fn block_once<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("once")) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        contents: call!(parse_template, s) >>
+        tag_s!(&*s.block_start) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endonce")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::Once(WS(pws1.is_some(), nws1.is_some()),
+                    contents,
+                    WS(pws2.is_some(), nws2.is_some())))
+    )
+}
+
+named!(autoescape_mode<&str>, alt!(
+    map!(alt!(tag_s!("true") | tag_s!("false")), |s| str::from_utf8(s).unwrap()) |
+    map!(expr_str_lit, |e| match e {
+        Expr::StrLit(s) => s,
+        _ => unreachable!(),
+    })
+));
+
+fn block_autoescape<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        pws1: opt!(tag_s!("-")) >>
+        ws!(tag_s!("autoescape")) >>
+        mode: ws!(autoescape_mode) >>
+        nws1: opt!(tag_s!("-")) >>
+        tag_s!(&*s.block_end) >>
+        contents: call!(parse_template, s) >>
+        tag_s!(&*s.block_start) >>
+        pws2: opt!(tag_s!("-")) >>
+        ws!(tag_s!("endautoescape")) >>
+        nws2: opt!(tag_s!("-")) >>
+        (Node::AutoEscape(WS(pws1.is_some(), nws1.is_some()),
+                          mode,
+                          contents,
+                          WS(pws2.is_some(), nws2.is_some())))
+    )
+}
+
+fn block_node<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        tag_s!(&*s.block_start) >>
+        contents: alt!(
+            block_call |
+            call!(block_let_else, s) |
+            block_let |
+            block_set |
+            call!(block_with, s) |
+            call!(block_if, s) |
+            call!(block_for, s) |
+            call!(block_match, s) |
+            block_extends |
+            block_include |
+            block_import |
+            call!(block_block, s) |
+            call!(block_macro, s) |
+            call!(block_component, s) |
+            call!(block_push, s) |
+            block_stack |
+            call!(block_once, s) |
+            call!(block_autoescape, s)
+        ) >>
+        tag_s!(&*s.block_end) >>
+        (contents)
+    )
+}
+
+fn block_comment<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Node<'a>> {
+    do_parse!(i,
+        tag_s!(&*s.comment_start) >>
+        pws: opt!(tag_s!("-")) >>
+        inner: take_until_s!(&*s.comment_end) >>
+        tag_s!(&*s.comment_end) >>
+        (Node::Comment(WS(pws.is_some(), inner.len() > 1 && inner[inner.len() - 1] == b'-')))
+    )
+}
+
+fn parse_template<'a>(i: &'a [u8], s: &Syntax) -> IResult<&'a [u8], Vec<Node<'a>>> {
+    many0!(i, alt!(
+        call!(take_content, s) |
+        call!(block_comment, s) |
+        call!(super_node, s) |
+        call!(expr_node, s) |
+        call!(block_node, s)
+    ))
+}
 
-pub fn parse(src: &str) -> Vec<Node> {
-    match parse_template(src.as_bytes()) {
+pub fn parse<'a>(src: &'a str, syntax: &Syntax) -> Vec<Node<'a>> {
+    match parse_template(src.as_bytes(), syntax) {
         IResult::Done(left, res) => {
             if !left.is_empty() {
                 let s = str::from_utf8(left).unwrap();
@@ -698,8 +1056,53 @@ mod tests {
         check_ws_split(" \t\r\n", &(" \t\r\n", "", ""));
     }
     #[test]
+    fn test_identifier() {
+        use nom::IResult;
+        assert_eq!(super::identifier(b"foo_1 "), IResult::Done(&b" "[..], "foo_1"));
+        assert_eq!(super::identifier(b"r#type}"), IResult::Done(&b"}"[..], "r#type"));
+        assert_eq!(super::identifier("größe.".as_bytes()), IResult::Done(&b"."[..], "größe"));
+        assert!(super::identifier(b"1a").is_err());
+        assert!(super::identifier(b"").is_err());
+    }
+    #[test]
+    fn test_custom_syntax() {
+        let syntax = super::Syntax {
+            block_start: "<%".to_owned(),
+            block_end: "%>".to_owned(),
+            ..super::Syntax::default()
+        };
+        let nodes = super::parse("{% a %}<% if b %>{{ c }}<% endif %>", &syntax);
+        assert_eq!(nodes.len(), 2);
+        match nodes[1] {
+            super::Node::Cond(ref conds, _) => assert_eq!(conds.len(), 1),
+            _ => panic!("expected if block"),
+        }
+    }
+    #[test]
+    fn test_reference() {
+        use super::{Expr, Node};
+        let syntax = super::Syntax::default();
+        match super::parse("{{ &a.b }}", &syntax)[0] {
+            Node::Expr(_, Expr::Unary("&", ref inner)) => match **inner {
+                Expr::Attr(ref obj, "b") => match **obj {
+                    Expr::Var("a") => {},
+                    _ => panic!("expected variable"),
+                },
+                _ => panic!("expected attribute"),
+            },
+            _ => panic!("expected reference"),
+        }
+        match super::parse("{{ a & &b }}", &syntax)[0] {
+            Node::Expr(_, Expr::BinOp("&", _, ref right)) => match **right {
+                Expr::Unary("&", _) => {},
+                _ => panic!("expected reference"),
+            },
+            _ => panic!("expected bitwise and"),
+        }
+    }
+    #[test]
     #[should_panic]
     fn test_invalid_block() {
-        super::parse("{% extend \"blah\" %}");
+        super::parse("{% extend \"blah\" %}", &super::Syntax::default());
     }
 }
@@ -0,0 +1,36 @@
+#[macro_use]
+extern crate bencher;
+extern crate askama_shared;
+
+use askama_shared::escaping::{Escaper, Html};
+use bencher::Bencher;
+
+benchmark_group!(benches, html_plain, html_mixed, html_markup);
+benchmark_main!(benches);
+
+// Prose with an escapable character every few hundred bytes.
+fn html_plain(b: &mut Bencher) {
+    escape(b, &"The quick brown fox jumps over the lazy dog, but not the cat. \
+                Then it takes a nap in the sun until dinner is ready & done.\n".repeat(64));
+}
+
+// Prose with digits and punctuation, but nothing to escape.
+fn html_mixed(b: &mut Bencher) {
+    escape(b, &"Order #12345 shipped on 2018-06-30 at 10:45; total: 99.95 (incl. tax), \
+                item count = 3, weight: 1.5kg, see section 4.2 for details!\n".repeat(64));
+}
+
+// Markup, with an escapable character every few bytes.
+fn html_markup(b: &mut Bencher) {
+    escape(b, &"<p class=\"intro\">Hello, <b>world</b> & 'friends'</p>\n".repeat(64));
+}
+
+fn escape(b: &mut Bencher, s: &str) {
+    let mut buf = String::with_capacity(s.len() * 2);
+    b.bytes = s.len() as u64;
+    b.iter(|| {
+        buf.clear();
+        Html.write_escaped(&mut buf, s).unwrap();
+        buf.len()
+    });
+}
@@ -0,0 +1,44 @@
+// Flattens the last link of an optional chain (`a&.b`) in generated code, so
+// that a field of type `Option<T>` yields an `Option<&T>` like a field of type
+// `T` does. Generated code calls `lift()` on a `&&Lift`; as with the
+// `escaping::Select` types, method resolution tries the impl for `&&Lift`
+// first, which only applies to `Option` values.
+
+#[doc(hidden)]
+pub struct Lift<'a, T: 'a + ?Sized>(pub &'a T);
+
+#[doc(hidden)]
+pub trait LiftOption<'a, T: 'a> {
+    fn lift(self) -> Option<&'a T>;
+}
+
+impl<'a, 'b, 'c, T> LiftOption<'a, T> for &'c &'b Lift<'a, Option<T>> {
+    fn lift(self) -> Option<&'a T> {
+        self.0.as_ref()
+    }
+}
+
+#[doc(hidden)]
+pub trait LiftValue<'a, T: 'a + ?Sized> {
+    fn lift(self) -> Option<&'a T>;
+}
+
+impl<'a, 'b, T> LiftValue<'a, T> for &'b Lift<'a, T> where T: ?Sized {
+    fn lift(self) -> Option<&'a T> {
+        Some(self.0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lift() {
+        assert_eq!((&&Lift(&Some(1))).lift(), Some(&1));
+        assert_eq!((&&Lift(&None::<u8>)).lift(), None);
+        assert_eq!((&&Lift(&1)).lift(), Some(&1));
+        assert_eq!((&&Lift("a")).lift(), Some("a"));
+    }
+}
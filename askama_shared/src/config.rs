@@ -0,0 +1,455 @@
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use toml;
+
+
+/// Project-wide settings, read from `askama.toml` in the crate root
+///
+/// All settings are optional. For example:
+///
+/// ```toml
+/// [general]
+/// # Directories to search for templates, in order, relative to the crate
+/// # root or to `$OUT_DIR`; directories listed in `ASKAMA_TEMPLATE_DIRS_<PKG>`
+/// # are searched first
+/// dirs = ["templates", "../shared/templates", "$OUT_DIR/templates"]
+/// # Syntax for templates that do not set one with the `syntax` key
+/// default_syntax = "default"
+/// # Whitespace handling: "preserve" or "suppress"
+/// whitespace = "preserve"
+/// # Default for the `print` key
+/// print = "none"
+///
+/// [[syntax]]
+/// name = "tex"
+/// block_start = "%{"
+/// block_end = "}%"
+/// # Templates with these extensions use this syntax by default
+/// extensions = ["tex"]
+///
+/// [[escaper]]
+/// path = "latex"
+/// extensions = ["tex"]
+/// ```
+pub struct Config {
+    pub dirs: Vec<PathBuf>,
+    pub inline_dir: PathBuf,
+    pub crates: BTreeMap<String, Vec<PathBuf>>,
+    pub syntaxes: BTreeMap<String, Syntax>,
+    pub default_syntax: String,
+    pub escapers: Vec<(HashSet<String>, String)>,
+    pub suppress_whitespace: bool,
+    pub print: String,
+}
+
+impl Config {
+    /// Parses and validates the contents of a configuration file, panicking
+    /// with a message describing the problem if it is not valid.
+    pub fn new(source: &str) -> Config {
+        let raw: RawConfig = match toml::from_str(source) {
+            Ok(raw) => raw,
+            Err(err) => panic!("invalid askama.toml: {}", err),
+        };
+        let general = raw.general.unwrap_or_default();
+
+        // Directories from the environment are searched before the
+        // configured ones, so that they can override their templates.
+        let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+        let mut dirs = Vec::new();
+        if let Some(paths) = env::var_os(template_dirs_var()) {
+            // Directories that do not exist are skipped, since the variable
+            // may be meant for a different build of the package
+            for dir in env::split_paths(&paths).filter(|dir| !dir.as_os_str().is_empty()) {
+                let path = root.join(dir);
+                if path.is_dir() {
+                    dirs.push(path);
+                }
+            }
+        }
+        // Template directories exported by dependencies, as passed on to the
+        // compiler by `askama::import_templates()`
+        let mut crates = BTreeMap::new();
+        for (key, value) in env::vars_os() {
+            let name = match key.to_str() {
+                Some(key) if key.starts_with(CRATE_TEMPLATES_VAR_PREFIX) => {
+                    key[CRATE_TEMPLATES_VAR_PREFIX.len()..].to_owned()
+                },
+                _ => continue,
+            };
+            crates.insert(name, env::split_paths(&value).collect());
+        }
+
+        let inline_dir;
+        match general.dirs {
+            Some(configured) => {
+                if configured.is_empty() {
+                    panic!("invalid askama.toml: no template directories configured");
+                }
+                let first = dirs.len();
+                for dir in configured {
+                    // Directories in `OUT_DIR` may not have been generated
+                    // yet when this is called from a build script. Crates
+                    // without a build script have no `OUT_DIR`, so there is
+                    // nothing to search there.
+                    if let Some(rel) = out_dir_relative(&dir) {
+                        if let Some(out_dir) = env::var_os("OUT_DIR") {
+                            dirs.push(PathBuf::from(out_dir).join(rel));
+                        }
+                        continue;
+                    }
+                    let path = root.join(dir);
+                    if !path.is_dir() {
+                        panic!("invalid askama.toml: template directory {:?} not found", path);
+                    }
+                    dirs.push(path);
+                }
+                inline_dir = dirs.get(first).cloned().unwrap_or_else(|| root.join("templates"));
+            },
+            None => {
+                inline_dir = root.join("templates");
+                dirs.push(inline_dir.clone());
+            },
+        }
+
+        let mut syntaxes = BTreeMap::new();
+        syntaxes.insert(DEFAULT_SYNTAX_NAME.to_owned(), Syntax::default());
+        let mut syntax_extensions = HashSet::new();
+        for syntax in raw.syntax.unwrap_or_default() {
+            syntax.validate();
+            if syntaxes.contains_key(&syntax.name) {
+                panic!("invalid askama.toml: syntax '{}' is defined more than once", syntax.name);
+            }
+            for ext in &syntax.extensions {
+                if !syntax_extensions.insert(ext.clone()) {
+                    panic!("invalid askama.toml: extension '{}' is used by more than one syntax",
+                           ext);
+                }
+            }
+            syntaxes.insert(syntax.name.clone(), syntax);
+        }
+        let default_syntax = general.default_syntax
+            .unwrap_or_else(|| DEFAULT_SYNTAX_NAME.to_owned());
+        if !syntaxes.contains_key(&default_syntax) {
+            panic!("invalid askama.toml: default syntax '{}' not found", default_syntax);
+        }
+
+        let mut escapers = Vec::new();
+        for escaper in raw.escaper.unwrap_or_default() {
+            if !is_escape_mode(&escaper.path) {
+                panic!("invalid askama.toml: invalid escaper path '{}'", escaper.path);
+            }
+            escapers.push((escaper.extensions.into_iter().collect(), escaper.path));
+        }
+        for &(extensions, mode) in DEFAULT_ESCAPERS {
+            escapers.push((extensions.iter().map(|s| s.to_string()).collect(), mode.to_owned()));
+        }
+
+        let suppress_whitespace = match general.whitespace.as_ref().map(|s| s.as_str()) {
+            None | Some("preserve") => false,
+            Some("suppress") => true,
+            Some(v) => panic!("invalid askama.toml: invalid value for whitespace: {}", v),
+        };
+        let print = general.print.unwrap_or_else(|| "none".to_owned());
+        match print.as_str() {
+            "none" | "ast" | "code" | "all" => {},
+            v => panic!("invalid askama.toml: invalid value for print: {}", v),
+        }
+
+        Config {
+            dirs, inline_dir, crates, syntaxes, default_syntax, escapers, suppress_whitespace, print,
+        }
+    }
+
+    /// Returns the syntax with the given name, or the default syntax.
+    pub fn syntax(&self, name: Option<&str>) -> &Syntax {
+        let name = name.unwrap_or(&self.default_syntax);
+        match self.syntaxes.get(name) {
+            Some(syntax) => syntax,
+            None => panic!("syntax '{}' not found", name),
+        }
+    }
+
+    /// Returns the syntax for the template at the given path: the one that
+    /// its extension is configured for, or the default syntax.
+    pub fn syntax_for(&self, path: &Path) -> &Syntax {
+        let ext = path.extension().and_then(|ext| ext.to_str());
+        self.syntaxes.values()
+            .find(|syntax| ext.map_or(false, |ext| syntax.extensions.iter().any(|e| e == ext)))
+            .unwrap_or_else(|| self.syntax(None))
+    }
+
+    /// Returns the escape mode for templates with the given extension.
+    pub fn escaper(&self, ext: &str) -> Option<&str> {
+        self.escapers.iter()
+            .find(|&&(ref extensions, _)| extensions.contains(ext))
+            .map(|&(_, ref mode)| mode.as_str())
+    }
+}
+
+/// Whether `mode` is the name of a built-in escape mode or the path to an
+/// escaper type, as accepted by the `escape` option and in `askama.toml`.
+pub fn is_escape_mode(mode: &str) -> bool {
+    if ESCAPE_MODES.contains(&mode) {
+        return true;
+    }
+    let path = if mode.starts_with("::") { &mode[2..] } else { mode };
+    path.split("::").all(|segment| {
+        let mut chars = segment.chars();
+        match chars.next() {
+            Some(c) if c == '_' || c.is_alphabetic() => {
+                chars.all(|c| c == '_' || c.is_alphanumeric()) && segment != "_"
+            },
+            _ => false,
+        }
+    })
+}
+
+// Returns the part of a configured directory following `$OUT_DIR`, if it
+// starts with it.
+fn out_dir_relative(dir: &str) -> Option<&str> {
+    if dir == OUT_DIR_VAR_REF {
+        Some("")
+    } else if dir.starts_with(OUT_DIR_VAR_REF) && dir[OUT_DIR_VAR_REF.len()..].starts_with('/') {
+        Some(&dir[OUT_DIR_VAR_REF.len() + 1..])
+    } else {
+        None
+    }
+}
+
+/// Returns the name of the environment variable holding template directories
+/// for the crate being built, which is `TEMPLATE_DIRS_VAR_PREFIX` followed by
+/// the package name in upper case, with `-` replaced by `_`.
+pub fn template_dirs_var() -> String {
+    let name = env::var("CARGO_PKG_NAME").unwrap_or_default();
+    format!("{}{}", TEMPLATE_DIRS_VAR_PREFIX, name.to_uppercase().replace('-', "_"))
+}
+
+/// Reads `askama.toml` from the crate root, returning an empty string if
+/// there is none.
+pub fn read_config_file() -> String {
+    let mut source = String::new();
+    if let Ok(mut f) = File::open(config_file_path()) {
+        f.read_to_string(&mut source).unwrap();
+    }
+    source
+}
+
+/// Returns the path of `askama.toml` in the crate root.
+pub fn config_file_path() -> PathBuf {
+    let mut path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    path.push(CONFIG_FILE_NAME);
+    path
+}
+
+
+/// The delimiters of a template syntax
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Syntax {
+    pub name: String,
+    pub block_start: String,
+    pub block_end: String,
+    pub expr_start: String,
+    pub expr_end: String,
+    pub comment_start: String,
+    pub comment_end: String,
+    pub extensions: Vec<String>,
+}
+
+impl Syntax {
+    fn validate(&self) {
+        if self.name == DEFAULT_SYNTAX_NAME {
+            panic!("invalid askama.toml: syntax name '{}' is reserved", self.name);
+        }
+        let delimiters = [&self.block_start, &self.block_end, &self.expr_start,
+                          &self.expr_end, &self.comment_start, &self.comment_end];
+        if delimiters.iter().any(|d| d.is_empty()) {
+            panic!("invalid askama.toml: empty delimiter in syntax '{}'", self.name);
+        }
+        let starts = [&self.block_start, &self.expr_start, &self.comment_start];
+        for (i, a) in starts.iter().enumerate() {
+            for b in &starts[i + 1..] {
+                if a.starts_with(b.as_str()) || b.starts_with(a.as_str()) {
+                    panic!("invalid askama.toml: ambiguous start delimiters {:?} and {:?} \
+                            in syntax '{}'", a, b, self.name);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Syntax {
+    fn default() -> Syntax {
+        Syntax {
+            name: DEFAULT_SYNTAX_NAME.to_owned(),
+            block_start: "{%".to_owned(),
+            block_end: "%}".to_owned(),
+            expr_start: "{{".to_owned(),
+            expr_end: "}}".to_owned(),
+            comment_start: "{#".to_owned(),
+            comment_end: "#}".to_owned(),
+            extensions: Vec::new(),
+        }
+    }
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    general: Option<General>,
+    syntax: Option<Vec<Syntax>>,
+    escaper: Option<Vec<RawEscaper>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct General {
+    dirs: Option<Vec<String>>,
+    default_syntax: Option<String>,
+    whitespace: Option<String>,
+    print: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEscaper {
+    path: String,
+    extensions: Vec<String>,
+}
+
+/// The prefix of the environment variable holding template directories to
+/// search before the configured ones, separated like the entries of `PATH`;
+/// see `template_dirs_var()`
+pub static TEMPLATE_DIRS_VAR_PREFIX: &str = "ASKAMA_TEMPLATE_DIRS_";
+/// The prefix of the environment variables holding the template directories
+/// of dependencies, followed by the name of the dependency
+pub static CRATE_TEMPLATES_VAR_PREFIX: &str = "ASKAMA_CRATE_TEMPLATES_";
+
+static CONFIG_FILE_NAME: &str = "askama.toml";
+static OUT_DIR_VAR_REF: &str = "$OUT_DIR";
+static DEFAULT_SYNTAX_NAME: &str = "default";
+static ESCAPE_MODES: &[&str] = &["html", "none", "js", "css", "url", "xml", "latex", "shell"];
+static DEFAULT_ESCAPERS: &[(&[&str], &str)] = &[
+    (&["html", "htm"], "html"),
+    (&["xml"], "xml"),
+];
+
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use std::path::Path;
+
+    #[test]
+    fn test_default_config() {
+        let config = Config::new("");
+        assert!(config.dirs[0].ends_with("templates"));
+        assert!(config.inline_dir.ends_with("templates"));
+        assert_eq!(config.syntax(None).block_start, "{%");
+        assert_eq!(config.escaper("htm"), Some("html"));
+        assert_eq!(config.escaper("txt"), None);
+        assert!(!config.suppress_whitespace);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = Config::new(r#"
+            [general]
+            dirs = ["templates", "templates/sub"]
+            default_syntax = "tex"
+            whitespace = "suppress"
+
+            [[syntax]]
+            name = "tex"
+            block_start = "%{"
+            block_end = "}%"
+
+            [[escaper]]
+            path = "latex"
+            extensions = ["tex", "html"]
+        "#);
+        assert_eq!(config.dirs.len(), 2);
+        let syntax = config.syntax(None);
+        assert_eq!((syntax.block_start.as_str(), syntax.expr_start.as_str()), ("%{", "{{"));
+        assert_eq!(config.syntax(Some("default")).block_start, "{%");
+        assert_eq!(config.escaper("html"), Some("latex"));
+        assert_eq!(config.escaper("xml"), Some("xml"));
+        assert!(config.suppress_whitespace);
+    }
+
+    #[test]
+    fn test_template_dirs_var() {
+        assert_eq!(super::template_dirs_var(), "ASKAMA_TEMPLATE_DIRS_ASKAMA_SHARED");
+    }
+
+    #[test]
+    fn test_out_dir_relative() {
+        assert_eq!(super::out_dir_relative("$OUT_DIR"), Some(""));
+        assert_eq!(super::out_dir_relative("$OUT_DIR/templates"), Some("templates"));
+        assert_eq!(super::out_dir_relative("$OUT_DIRS"), None);
+        assert_eq!(super::out_dir_relative("templates"), None);
+    }
+
+    #[test]
+    fn test_syntax_for() {
+        let config = Config::new("[[syntax]]\nname = \"tex\"\nblock_start = \"%{\"\n\
+                                  extensions = [\"tex\"]");
+        assert_eq!(config.syntax_for(Path::new("a.tex")).name, "tex");
+        assert_eq!(config.syntax_for(Path::new("a.html")).name, "default");
+        assert_eq!(config.syntax_for(Path::new("a")).name, "default");
+    }
+
+    #[test]
+    fn test_escape_modes() {
+        assert!(super::is_escape_mode("html"));
+        assert!(super::is_escape_mode("::my_crate::escapers::Shout"));
+        assert!(super::is_escape_mode("Shout"));
+        assert!(!super::is_escape_mode("Shout()"));
+        assert!(!super::is_escape_mode("my crate::Shout"));
+        assert!(!super::is_escape_mode("crate::"));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid askama.toml: invalid escaper path 'a-b'")]
+    fn test_invalid_escaper() {
+        Config::new("[[escaper]]\npath = \"a-b\"\nextensions = [\"x\"]");
+    }
+
+    #[test]
+    #[should_panic(expected = "extension 'x' is used by more than one syntax")]
+    fn test_ambiguous_syntax_extension() {
+        Config::new("[[syntax]]\nname = \"a\"\nblock_start = \"%{\"\nextensions = [\"x\"]\n\
+                     [[syntax]]\nname = \"b\"\nblock_start = \"%{\"\nextensions = [\"x\"]");
+    }
+
+    #[test]
+    fn test_out_dir_without_build_script() {
+        // There is no `OUT_DIR` when testing this crate
+        let config = Config::new("[general]\ndirs = [\"$OUT_DIR/templates\", \"src\"]");
+        assert_eq!(config.dirs.len(), 1);
+        assert!(config.dirs[0].ends_with("src"));
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown field")]
+    fn test_unknown_key() {
+        Config::new("[general]\ndir = [\"templates\"]");
+    }
+
+    #[test]
+    #[should_panic(expected = "default syntax 'foo' not found")]
+    fn test_unknown_syntax() {
+        Config::new("[general]\ndefault_syntax = \"foo\"");
+    }
+
+    #[test]
+    #[should_panic(expected = "ambiguous start delimiters")]
+    fn test_ambiguous_syntax() {
+        Config::new("[[syntax]]\nname = \"x\"\nblock_start = \"{\"");
+    }
+}
@@ -1,84 +1,467 @@
-use std::fmt::{self, Display, Formatter};
+use std::fmt::{self, Display, Formatter, Write};
 
 
-#[derive(Debug, PartialEq)]
-pub enum MarkupDisplay<T> where T: Display {
-    Safe(T),
-    Unsafe(T),
+/// Escapes text for a specific output format
+///
+/// An escaper is selected for a template with the `escape` key of the
+/// `template()` attribute, which takes the name of one of the escapers in
+/// this module or the path to a type implementing this trait.
+pub trait Escaper {
+    /// Writes `string` to `fmt`, escaping what is needed for the format.
+    fn write_escaped<W>(&self, fmt: W, string: &str) -> fmt::Result where W: Write;
+
+    /// Whether values can be escaped in parts, as they are being formatted.
+    ///
+    /// By default, values are formatted into a `String` before they are
+    /// escaped, so that the escaper sees them as a whole, for example to
+    /// check their prefix. Escapers that handle each character on its own
+    /// can return `true` to skip this allocation.
+    fn streaming(&self) -> bool {
+        false
+    }
 }
 
-impl<T> MarkupDisplay<T> where T: Display {
-    pub fn mark_safe(self) -> MarkupDisplay<T> {
-        match self {
-            MarkupDisplay::Unsafe(t) => MarkupDisplay::Safe(t),
-            _ => self,
+
+/// Escapes HTML: `<`, `>`, `&`, `"`, `'` and `/`
+#[derive(Clone, Copy, Debug)]
+pub struct Html;
+
+impl Escaper for Html {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> fmt::Result where W: Write {
+        let bytes = string.as_bytes();
+        let mut start = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            // Most text has nothing to escape, so it is skipped a word at a
+            // time, without pulling in a dependency for SIMD searching.
+            if i + 8 <= bytes.len() && !any_escapable(&bytes[i..i + 8]) {
+                i += 8;
+                continue;
+            }
+            if !escapable(bytes[i]) {
+                i += 1;
+                continue;
+            }
+            if start < i {
+                fmt.write_str(&string[start..i])?;
+            }
+            fmt.write_str(match bytes[i] {
+                b'<' => "&lt;",
+                b'>' => "&gt;",
+                b'&' => "&amp;",
+                b'"' => "&quot;",
+                b'\'' => "&#x27;",
+                _ => "&#x2f;",
+            })?;
+            i += 1;
+            start = i;
+        }
+        if start < bytes.len() {
+            fmt.write_str(&string[start..])?;
         }
+        Ok(())
     }
-    pub fn unsafe_string(&self) -> String {
-        match *self {
-            MarkupDisplay::Safe(ref t) | MarkupDisplay::Unsafe(ref t) => format!("{}", t),
+
+    fn streaming(&self) -> bool {
+        true
+    }
+}
+
+fn escapable(b: u8) -> bool {
+    match b {
+        b'<' | b'>' | b'&' | b'"' | b'\'' | b'/' => true,
+        _ => false,
+    }
+}
+
+// Whether any of eight bytes is escapable, checking them all at once: a byte
+// of `word ^ repeat(c)` is zero where `word` has `c`, which sets its high bit
+// in `(x - repeat(1)) & !x`. The high bits for all escapable characters are
+// combined, so that a single test tells whether any of them was found.
+fn any_escapable(chunk: &[u8]) -> bool {
+    const ONES: u64 = 0x0101_0101_0101_0101;
+    const HIGHS: u64 = 0x8080_8080_8080_8080;
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(chunk);
+    let word = u64::from_le_bytes(bytes);
+    let zeros = |c: u8| {
+        let x = word ^ (ONES * u64::from(c));
+        x.wrapping_sub(ONES) & !x
+    };
+    (zeros(b'<') | zeros(b'>') | zeros(b'&') | zeros(b'"') | zeros(b'\'') | zeros(b'/')) & HIGHS != 0
+}
+
+/// Leaves text unchanged
+#[derive(Clone, Copy, Debug)]
+pub struct Text;
+
+impl Escaper for Text {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> fmt::Result where W: Write {
+        fmt.write_str(string)
+    }
+
+    fn streaming(&self) -> bool {
+        true
+    }
+}
+
+/// Escapes text for use in JavaScript string literals
+///
+/// Besides quotes and backslashes, `<`, `>` and `&` are escaped as well, so
+/// that the string cannot end a surrounding `<script>` element. Quotes are
+/// written as Unicode escapes, which makes the output safe to use in HTML
+/// attribute values, too.
+#[derive(Clone, Copy, Debug)]
+pub struct Js;
+
+impl Escaper for Js {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> fmt::Result where W: Write {
+        for c in string.chars() {
+            match c {
+                '\\' => fmt.write_str("\\\\")?,
+                '\n' => fmt.write_str("\\n")?,
+                '\r' => fmt.write_str("\\r")?,
+                '\t' => fmt.write_str("\\t")?,
+                '"' | '\'' | '<' | '>' | '&' | '=' | '`' | '\u{2028}' | '\u{2029}' => {
+                    write!(fmt, "\\u{:04x}", c as u32)?
+                },
+                c if (c as u32) < 0x20 => write!(fmt, "\\u{:04x}", c as u32)?,
+                c => fmt.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn streaming(&self) -> bool {
+        true
+    }
+}
+
+/// Escapes text for use in CSS strings and identifiers
+///
+/// All ASCII characters except letters, digits, `-` and `_` are written as
+/// hexadecimal escapes.
+#[derive(Clone, Copy, Debug)]
+pub struct Css;
+
+impl Escaper for Css {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> fmt::Result where W: Write {
+        for c in string.chars() {
+            match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => fmt.write_char(c)?,
+                c if c.is_ascii() => write!(fmt, "\\{:x} ", c as u32)?,
+                c => fmt.write_char(c)?,
+            }
         }
+        Ok(())
+    }
+
+    fn streaming(&self) -> bool {
+        true
     }
 }
 
-impl<T> From<T> for MarkupDisplay<T> where T: Display {
-    fn from(t: T) -> MarkupDisplay<T> {
-        MarkupDisplay::Unsafe(t)
+/// Percent-encodes text for use as a URL component
+///
+/// Only the unreserved characters of RFC 3986 are left unchanged.
+#[derive(Clone, Copy, Debug)]
+pub struct Url;
+
+impl Escaper for Url {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> fmt::Result where W: Write {
+        for b in string.bytes() {
+            match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    fmt.write_char(b as char)?
+                },
+                b => write!(fmt, "%{:02X}", b)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn streaming(&self) -> bool {
+        true
     }
 }
 
-impl<T> Display for MarkupDisplay<T> where T: Display {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match *self {
-            MarkupDisplay::Unsafe(_) => write!(f, "{}", escape(self.unsafe_string())),
-            MarkupDisplay::Safe(ref t) => t.fmt(f),
+/// Escapes a complete URL for use in an HTML attribute value
+///
+/// URLs with a scheme other than `http`, `https` or `mailto` are replaced by
+/// `#`, so that no `javascript:` URLs can be injected. Otherwise, characters
+/// that are not allowed in URLs are percent-encoded, like with `HtmlUrlPath`.
+#[derive(Clone, Copy, Debug)]
+pub struct HtmlUrl;
+
+impl Escaper for HtmlUrl {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> fmt::Result where W: Write {
+        if let Some(end) = string.find(|c| c == ':' || c == '/' || c == '?' || c == '#') {
+            if string[end..].starts_with(':') {
+                let scheme = string[..end].to_ascii_lowercase();
+                if scheme != "http" && scheme != "https" && scheme != "mailto" {
+                    return fmt.write_char('#');
+                }
+            }
         }
+        HtmlUrlPath.write_escaped(fmt, string)
     }
 }
 
+/// Escapes part of a URL for use in an HTML attribute value
+///
+/// Characters that are not allowed in URLs, as well as quotes, are
+/// percent-encoded, and `&` is written as `&amp;`.
+#[derive(Clone, Copy, Debug)]
+pub struct HtmlUrlPath;
 
-fn escapable(b: u8) -> bool {
-    match b {
-        b'<' | b'>' | b'&' | b'"' | b'\'' | b'/' => true,
-        _ => false,
+impl Escaper for HtmlUrlPath {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> fmt::Result where W: Write {
+        for b in string.bytes() {
+            match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' |
+                b':' | b'/' | b'?' | b'#' | b'[' | b']' | b'@' | b'!' | b'$' | b'(' |
+                b')' | b'*' | b'+' | b',' | b';' | b'=' | b'%' => fmt.write_char(b as char)?,
+                b'&' => fmt.write_str("&amp;")?,
+                b => write!(fmt, "%{:02X}", b)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn streaming(&self) -> bool {
+        true
     }
 }
 
-pub fn escape(s: String) -> String {
-    let mut found = Vec::new();
-    for (i, b) in s.as_bytes().iter().enumerate() {
-        if escapable(*b) {
-            found.push(i);
+/// Escapes XML: `<`, `>`, `&`, `"` and `'`
+#[derive(Clone, Copy, Debug)]
+pub struct Xml;
+
+impl Escaper for Xml {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> fmt::Result where W: Write {
+        for c in string.chars() {
+            match c {
+                '<' => fmt.write_str("&lt;")?,
+                '>' => fmt.write_str("&gt;")?,
+                '&' => fmt.write_str("&amp;")?,
+                '"' => fmt.write_str("&quot;")?,
+                '\'' => fmt.write_str("&apos;")?,
+                c => fmt.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn streaming(&self) -> bool {
+        true
+    }
+}
+
+/// Escapes the characters that are special in LaTeX
+#[derive(Clone, Copy, Debug)]
+pub struct Latex;
+
+impl Escaper for Latex {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> fmt::Result where W: Write {
+        for c in string.chars() {
+            match c {
+                '\\' => fmt.write_str("\\textbackslash{}")?,
+                '~' => fmt.write_str("\\textasciitilde{}")?,
+                '^' => fmt.write_str("\\textasciicircum{}")?,
+                '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                    fmt.write_char('\\')?;
+                    fmt.write_char(c)?;
+                },
+                c => fmt.write_char(c)?,
+            }
         }
+        Ok(())
+    }
+
+    fn streaming(&self) -> bool {
+        true
     }
-    if found.is_empty() {
-        return s;
+}
+
+/// Quotes text as a single POSIX shell word
+///
+/// The text is wrapped in single quotes, so that it is never interpreted by
+/// the shell.
+#[derive(Clone, Copy, Debug)]
+pub struct Shell;
+
+impl Escaper for Shell {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> fmt::Result where W: Write {
+        fmt.write_char('\'')?;
+        for (i, part) in string.split('\'').enumerate() {
+            if i > 0 {
+                fmt.write_str("'\\''")?;
+            }
+            fmt.write_str(part)?;
+        }
+        fmt.write_char('\'')
     }
+}
 
-    let bytes = s.as_bytes();
-    let max_len = bytes.len() + found.len() * 5;
-    let mut res = Vec::<u8>::with_capacity(max_len);
-    let mut start = 0;
-    for idx in &found {
-        if start < *idx {
-            res.extend(&bytes[start..*idx]);
+
+/// A value to be displayed in a template, which is escaped with the given
+/// escaper unless it is marked as safe
+pub struct MarkupDisplay<E, T> where E: Escaper, T: Display {
+    value: DisplayValue<T>,
+    escaper: E,
+}
+
+enum DisplayValue<T> where T: Display {
+    Safe(T),
+    Unsafe(T),
+}
+
+impl<E, T> MarkupDisplay<E, T> where E: Escaper, T: Display {
+    pub fn new_unsafe(value: T, escaper: E) -> MarkupDisplay<E, T> {
+        MarkupDisplay { value: DisplayValue::Unsafe(value), escaper }
+    }
+    pub fn new_safe(value: T, escaper: E) -> MarkupDisplay<E, T> {
+        MarkupDisplay { value: DisplayValue::Safe(value), escaper }
+    }
+    pub fn mark_safe(mut self) -> MarkupDisplay<E, T> {
+        self.value = match self.value {
+            DisplayValue::Unsafe(t) => DisplayValue::Safe(t),
+            value => value,
+        };
+        self
+    }
+    pub fn unsafe_string(&self) -> String {
+        match self.value {
+            DisplayValue::Safe(ref t) | DisplayValue::Unsafe(ref t) => format!("{}", t),
         }
-        start = *idx + 1;
-        match bytes[*idx] {
-            b'<' => { res.extend(b"&lt;"); },
-            b'>' => { res.extend(b"&gt;"); },
-            b'&' => { res.extend(b"&amp;"); },
-            b'"' => { res.extend(b"&quot;"); },
-            b'\'' => { res.extend(b"&#x27;"); },
-            b'/' => { res.extend(b"&#x2f;"); },
-            _ => panic!("incorrect indexing"),
+    }
+}
+
+impl<E, T> Display for MarkupDisplay<E, T> where E: Escaper, T: Display {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.value {
+            DisplayValue::Unsafe(ref t) if self.escaper.streaming() => {
+                write!(EscapeWriter { fmt: f, escaper: &self.escaper }, "{}", t)
+            },
+            DisplayValue::Unsafe(_) => self.escaper.write_escaped(f, &self.unsafe_string()),
+            DisplayValue::Safe(ref t) => t.fmt(f),
         }
     }
-    if start < bytes.len() - 1 {
-        res.extend(&bytes[start..]);
+}
+
+
+/// Marker for types whose `Display` output never needs to be escaped in HTML
+///
+/// Values of these types are written to the output as they are in HTML text
+/// and plain attribute values, without going through the escaper. In other
+/// contexts, like `<script>` elements or URL attributes, they are escaped
+/// like any other value. It is implemented for numbers and `bool`, and can
+/// be implemented for other types, as long as their output cannot contain
+/// any of the characters escaped by `Html`.
+pub trait HtmlSafe: Display {}
+
+macro_rules! html_safe {
+    ($($t:ty),*) => {
+        $(impl HtmlSafe for $t {})*
+    };
+}
+
+html_safe!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool);
+
+impl<'a, T> HtmlSafe for &'a T where T: HtmlSafe + ?Sized {}
+
+
+/// Values that are markup already, and are therefore not escaped when they
+/// are written to HTML templates
+///
+/// Templates that escape HTML implement this trait, so that they can be
+/// embedded in other templates as they are, in HTML text and plain attribute
+/// values; in other contexts, they are escaped. Implement it for other types
+/// only if their output is known to be safe HTML, for example because it
+/// was sanitized. To mark a single value as markup, use `PreEscaped`.
+pub trait Markup: Display {}
+
+impl<'a, T> Markup for &'a T where T: Markup + ?Sized {}
+
+/// A value that is written to HTML templates without escaping
+#[derive(Clone, Copy, Debug)]
+pub struct PreEscaped<T>(pub T) where T: Display;
+
+impl<T> Display for PreEscaped<T> where T: Display {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T> Markup for PreEscaped<T> where T: Display {}
+
+
+// Chooses between writing a value as it is or escaping it, based on whether
+// its type implements `Markup` or `HtmlSafe`. Generated code calls `select()`
+// on a `&&Select`. Method resolution tries the traits implemented for
+// `&&Select`, `&Select` and `Select` in that order, skipping those whose
+// bounds the value does not meet.
+#[doc(hidden)]
+pub struct Select<'a, T: 'a + ?Sized>(pub &'a T);
+
+impl<'a, T: ?Sized> Clone for Select<'a, T> {
+    fn clone(&self) -> Self {
+        Select(self.0)
+    }
+}
+
+impl<'a, T: ?Sized> Copy for Select<'a, T> {}
+
+#[doc(hidden)]
+pub trait SelectMarkup<'a, T: 'a + Display + ?Sized> {
+    fn select<E: Escaper>(self, escaper: E) -> MarkupDisplay<E, &'a T>;
+}
+
+impl<'a, 'b, 'c, T> SelectMarkup<'a, T> for &'c &'b Select<'a, T> where T: Markup + ?Sized {
+    fn select<E: Escaper>(self, escaper: E) -> MarkupDisplay<E, &'a T> {
+        MarkupDisplay::new_safe(self.0, escaper)
     }
+}
 
-    String::from_utf8(res).unwrap()
+#[doc(hidden)]
+pub trait SelectSafe<'a, T: 'a + Display + ?Sized> {
+    fn select<E: Escaper>(self, escaper: E) -> MarkupDisplay<E, &'a T>;
+}
+
+impl<'a, 'b, T> SelectSafe<'a, T> for &'b Select<'a, T> where T: HtmlSafe + ?Sized {
+    fn select<E: Escaper>(self, escaper: E) -> MarkupDisplay<E, &'a T> {
+        MarkupDisplay::new_safe(self.0, escaper)
+    }
+}
+
+#[doc(hidden)]
+pub trait SelectEscaped<'a, T: 'a + Display + ?Sized> {
+    fn select<E: Escaper>(self, escaper: E) -> MarkupDisplay<E, &'a T>;
+}
+
+impl<'a, T> SelectEscaped<'a, T> for Select<'a, T> where T: Display + ?Sized {
+    fn select<E: Escaper>(self, escaper: E) -> MarkupDisplay<E, &'a T> {
+        MarkupDisplay::new_unsafe(self.0, escaper)
+    }
+}
+
+
+// Escapes everything written to it before passing it on.
+struct EscapeWriter<'a, 'b: 'a, E: 'a> {
+    fmt: &'a mut Formatter<'b>,
+    escaper: &'a E,
+}
+
+impl<'a, 'b, E> Write for EscapeWriter<'a, 'b, E> where E: Escaper {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.escaper.write_escaped(&mut *self.fmt, s)
+    }
+}
+
+pub fn escape(s: String) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    Html.write_escaped(&mut escaped, &s).unwrap();
+    escaped
 }
 
 
@@ -91,5 +474,45 @@ mod tests {
         assert_eq!(escape("<&>".to_string()), "&lt;&amp;&gt;");
         assert_eq!(escape("bla&".to_string()), "bla&amp;");
         assert_eq!(escape("<foo".to_string()), "&lt;foo");
+        assert_eq!(escape("<a>b".to_string()), "&lt;a&gt;b");
+        assert_eq!(escape("it's".to_string()), "it&#x27;s");
+        assert_eq!(escape("a long text without markup".to_string()),
+                   "a long text without markup");
+        assert_eq!(escape("a long text with <b>markup</b> in it".to_string()),
+                   "a long text with &lt;b&gt;markup&lt;&#x2f;b&gt; in it");
+        assert_eq!(escape("ending with an ampersand: &".to_string()),
+                   "ending with an ampersand: &amp;");
+    }
+
+    fn escaped<E: Escaper>(escaper: E, s: &str) -> String {
+        format!("{}", MarkupDisplay::new_unsafe(s, escaper))
+    }
+
+    #[test]
+    fn test_escapers() {
+        assert_eq!(escaped(Text, "<a & b>"), "<a & b>");
+        assert_eq!(escaped(Js, "it's \"</script>\"\n"),
+                   "it\\u0027s \\u0022\\u003c/script\\u003e\\u0022\\n");
+        assert_eq!(escaped(Css, "a b;"), "a\\20 b\\3b ");
+        assert_eq!(escaped(Url, "a b/ä"), "a%20b%2F%C3%A4");
+        assert_eq!(escaped(HtmlUrl, "/a b?x=1&y='2'"), "/a%20b?x=1&amp;y=%272%27");
+        assert_eq!(escaped(HtmlUrl, "JavaScript:alert(1)"), "#");
+        assert_eq!(escaped(HtmlUrl, "https://example.com/"), "https://example.com/");
+        assert_eq!(escaped(HtmlUrlPath, "javascript:x\""), "javascript:x%22");
+        assert_eq!(escaped(Xml, "<'&'>"), "&lt;&apos;&amp;&apos;&gt;");
+        assert_eq!(escaped(Latex, "50% of $x_1"), "50\\% of \\$x\\_1");
+        assert_eq!(escaped(Shell, "it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_markup_display() {
+        assert_eq!(format!("{}", MarkupDisplay::new_unsafe("<a>", Html)), "&lt;a&gt;");
+        assert_eq!(format!("{}", MarkupDisplay::new_safe("<a>", Html)), "<a>");
+        assert_eq!(format!("{}", MarkupDisplay::new_unsafe("<a>", Html).mark_safe()), "<a>");
+        assert_eq!(format!("{}", (&&Select(&1.5)).select(Html)), "1.5");
+        assert_eq!(format!("{}", (&&Select("<a>")).select(Html)), "&lt;a&gt;");
+        assert_eq!(format!("{}", (&&Select(&PreEscaped("<a>"))).select(Html)), "<a>");
+        assert_eq!(format!("{}", MarkupDisplay::new_unsafe(format_args!("{}{}", "java", "script:x"),
+                                                            HtmlUrl)), "#");
     }
 }
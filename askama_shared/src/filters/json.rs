@@ -1,7 +1,7 @@
 use serde::Serialize;
 use serde_json;
 use errors::{Error, Result};
-use MarkupDisplay;
+use {Escaper, MarkupDisplay};
 
 
 /// Serialize to JSON (requires `serde-json` feature)
@@ -10,9 +10,9 @@ use MarkupDisplay;
 ///
 /// This will panic if `S`'s implementation of `Serialize` decides to fail,
 /// or if `T` contains a map with non-string keys.
-pub fn json<S: Serialize>(s: &S) -> Result<MarkupDisplay<String>> {
+pub fn json<E: Escaper, S: Serialize>(e: E, s: &S) -> Result<MarkupDisplay<E, String>> {
     match serde_json::to_string_pretty(s) {
-        Ok(s) => Ok(MarkupDisplay::Safe(s)),
+        Ok(s) => Ok(MarkupDisplay::new_safe(s, e)),
         Err(e) => Err(Error::from(e)),
     }
 }
@@ -21,13 +21,14 @@ pub fn json<S: Serialize>(s: &S) -> Result<MarkupDisplay<String>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use escaping::Html;
 
     #[test]
     fn test_json() {
-        assert_eq!(json(&true).unwrap().unsafe_string(), "true");
-        assert_eq!(json(&"foo").unwrap().unsafe_string(), r#""foo""#);
+        assert_eq!(json(Html, &true).unwrap().unsafe_string(), "true");
+        assert_eq!(json(Html, &"foo").unwrap().unsafe_string(), r#""foo""#);
         assert_eq!(
-            json(&vec!["foo", "bar"]).unwrap().unsafe_string(),
+            json(Html, &vec!["foo", "bar"]).unwrap().unsafe_string(),
             r#"[
   "foo",
   "bar"
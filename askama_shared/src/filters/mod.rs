@@ -9,9 +9,10 @@ mod json;
 #[cfg(feature = "serde-json")]
 pub use self::json::json;
 
-use std::fmt;
+use std::fmt::{self, Write};
 
-use escaping::{self, MarkupDisplay};
+use escaping::{Escaper, MarkupDisplay};
+use indent::IndentWriter;
 use super::Result;
 
 
@@ -19,10 +20,12 @@ use super::Result;
 // Askama or should refer to a local `filters` module. It should contain all the
 // filters shipped with Askama, even the optional ones (since optional inclusion
 // in the const vector based on features seems impossible right now).
-pub const BUILT_IN_FILTERS: [&str; 10] = [
+pub const BUILT_IN_FILTERS: [&str; 12] = [
+    "default",
     "e",
     "escape",
     "format",
+    "indent",
     "lower",
     "lowercase",
     "safe",
@@ -33,32 +36,37 @@ pub const BUILT_IN_FILTERS: [&str; 10] = [
 ];
 
 
-pub fn safe<D, I>(v: I) -> Result<MarkupDisplay<D>>
+/// Marks a value as safe, so that it is not escaped
+///
+/// Like `escape()`, this filter is passed the template's escaper by the
+/// code generator.
+pub fn safe<E, T>(e: E, v: T) -> Result<MarkupDisplay<E, T>>
 where
-    D: fmt::Display,
-    MarkupDisplay<D>: From<I>
+    E: Escaper,
+    T: fmt::Display
 {
-    let res: MarkupDisplay<D> = v.into();
-    Ok(res.mark_safe())
+    Ok(MarkupDisplay::new_safe(v, e))
 }
 
-/// Escapes `&`, `<` and `>` in strings
-pub fn escape<D, I>(i: I) -> Result<MarkupDisplay<String>>
+/// Escapes the value with the template's escaper
+///
+/// The code generator passes the escaper of the template, or the HTML
+/// escaper in templates that are not escaped.
+pub fn escape<E, T>(e: E, v: T) -> Result<MarkupDisplay<E, T>>
 where
-    D: fmt::Display,
-    MarkupDisplay<D>: From<I>
+    E: Escaper,
+    T: fmt::Display
 {
-    let md: MarkupDisplay<D> = i.into();
-    Ok(MarkupDisplay::Safe(escaping::escape(md.unsafe_string())))
+    Ok(MarkupDisplay::new_unsafe(v, e))
 }
 
 /// Alias for the `escape()` filter
-pub fn e<D, I>(i: I) -> Result<MarkupDisplay<String>>
+pub fn e<E, T>(e: E, v: T) -> Result<MarkupDisplay<E, T>>
 where
-    D: fmt::Display,
-    MarkupDisplay<D>: From<I>
+    E: Escaper,
+    T: fmt::Display
 {
-    escape(i)
+    escape(e, v)
 }
 
 /// Formats arguments according to the specified format
@@ -97,6 +105,31 @@ pub fn trim(s: &fmt::Display) -> Result<String> {
     Ok(s.trim().to_owned())
 }
 
+/// Indents all lines but the first (unless `first` is true) with `width` spaces
+///
+/// Blank lines are not indented. In templates, `first` can be left out, so
+/// that `{{ fields|indent(4) }}` is equivalent to `{{ fields|indent(4, false) }}`.
+/// Filter arguments are positional, so `first` cannot be given by name.
+pub fn indent(s: &fmt::Display, width: &usize, first: &bool) -> Result<String> {
+    let mut buf = String::new();
+    write!(IndentWriter::new(&mut buf, *width, *first), "{}", s)?;
+    Ok(buf)
+}
+
+/// Renders the contained value, or the fallback if there is none
+///
+/// The `??` operator in templates is shorthand for this filter, so that
+/// `{{ name ?? "Anonymous" }}` is equivalent to `{{ name|default("Anonymous") }}`.
+pub fn default<T, D>(value: &Option<T>, fallback: &D) -> Result<String>
+    where T: fmt::Display,
+          D: fmt::Display
+{
+    Ok(match *value {
+        Some(ref v) => format!("{}", v),
+        None => format!("{}", fallback),
+    })
+}
+
 /// Joins iterable into a string separated by provided argument
 pub fn join<T, I, S>(input: I, separator: S) -> Result<String>
     where T: fmt::Display,
@@ -130,6 +163,12 @@ mod tests {
         assert_eq!(lower(&"foo").unwrap(), "foo");
     }
 
+    #[test]
+    fn test_indent() {
+        assert_eq!(indent(&"foo\nbar", &2, &false).unwrap(), "foo\n  bar");
+        assert_eq!(indent(&"foo\n\nbar", &2, &true).unwrap(), "  foo\n\n  bar");
+    }
+
     #[test]
     fn test_upper() {
         assert_eq!(upper(&"Foo").unwrap(), "FOO");
@@ -143,6 +182,13 @@ mod tests {
         assert_eq!(trim(&" Hello\tworld\t").unwrap(), "Hello\tworld");
     }
 
+    #[test]
+    fn test_default() {
+        assert_eq!(default(&Some("foo"), &"bar").unwrap(), "foo");
+        assert_eq!(default(&None::<&str>, &"bar").unwrap(), "bar");
+        assert_eq!(default(&Some(&1), &0).unwrap(), "1");
+    }
+
     #[test]
     fn test_join() {
         assert_eq!(
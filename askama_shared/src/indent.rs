@@ -0,0 +1,57 @@
+use std::fmt::{self, Write};
+
+
+/// A `fmt::Write` adapter that indents the lines written through it.
+///
+/// Every line except the first one (unless `first` is set) is prefixed with
+/// `width` spaces. Blank lines are left empty. The code generator uses this
+/// for `{% include "..." indent 4 %}`.
+pub struct IndentWriter<'a> {
+    inner: &'a mut Write,
+    width: usize,
+    indent_next: bool,
+}
+
+impl<'a> IndentWriter<'a> {
+    pub fn new(inner: &'a mut Write, width: usize, first: bool) -> IndentWriter<'a> {
+        IndentWriter { inner, width, indent_next: first }
+    }
+}
+
+impl<'a> Write for IndentWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.inner.write_char('\n')?;
+                self.indent_next = true;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            if self.indent_next {
+                write!(self.inner, "{:1$}", "", self.width)?;
+                self.indent_next = false;
+            }
+            self.inner.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_indent_writer() {
+        let mut buf = String::new();
+        {
+            let mut writer = IndentWriter::new(&mut buf, 2, false);
+            writer.write_str("a\nb").unwrap();
+            writer.write_str("c\n\n").unwrap();
+            writer.write_str("d").unwrap();
+        }
+        assert_eq!(buf, "a\n  bc\n\n  d");
+    }
+}
@@ -3,17 +3,26 @@
 #[macro_use]
 extern crate error_chain;
 
-#[cfg(feature = "serde-json")]
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 #[cfg(feature = "serde-json")]
 extern crate serde_json;
+extern crate toml;
 
-pub use escaping::MarkupDisplay;
-pub use errors::{Error, Result};
+pub use escaping::{Escaper, HtmlSafe, Markup, MarkupDisplay, PreEscaped};
+pub use errors::{Error, ErrorKind, Result};
+pub use indent::IndentWriter;
+pub use stacks::{StackBuffer, StackWriter, Stacks};
+#[doc(hidden)]
+pub mod chain;
+pub mod config;
+pub mod escaping;
 pub mod filters;
 pub mod path;
 
-mod escaping;
+mod indent;
+mod stacks;
 
 mod errors {
     error_chain! {
@@ -21,5 +30,17 @@ mod errors {
             Fmt(::std::fmt::Error);
             Json(::serde_json::Error) #[cfg(feature = "serde-json")];
         }
+        errors {
+            /// The requested block is not defined in the template
+            BlockNotFound(name: String) {
+                description("block not found")
+                display("block '{}' not found", name)
+            }
+            /// An error returned by user code, propagated with the `?` operator
+            Custom(err: Box<::std::error::Error + Send + Sync>) {
+                description("error from template code")
+                display("{}", err)
+            }
+        }
     }
 }
@@ -1,12 +1,13 @@
+use config::Config;
+
 use std::env;
 use std::fs::File;
 use std::io::Read;
+use std::iter;
 use std::path::{Path, PathBuf};
 
-pub fn get_template_source(tpl_path: &Path) -> String {
-    let mut path = template_dir();
-    path.push(tpl_path);
-    let mut f = match File::open(&path) {
+pub fn get_template_source(path: &Path) -> String {
+    let mut f = match File::open(path) {
         Err(_) => {
             let msg = format!("unable to open template file '{}'", &path.to_str().unwrap());
             panic!(msg)
@@ -21,64 +22,167 @@ pub fn get_template_source(tpl_path: &Path) -> String {
     s
 }
 
-pub fn find_template_from_path(path: &str, start_at: Option<&Path>) -> PathBuf {
-    let root = template_dir();
-    if let Some(rel) = start_at {
-        let mut fs_rel_path = root.clone();
-        fs_rel_path.push(rel);
-        fs_rel_path = fs_rel_path.with_file_name(path);
-        if fs_rel_path.exists() {
-            return fs_rel_path.strip_prefix(&root).unwrap().to_owned();
-        }
+// Finds a template in `OUT_DIR`, as generated by the build script. Templates
+// in `OUT_DIR` are only looked up there, to avoid picking up another template
+// with the same name.
+pub fn find_template_in_out_dir(path: &str) -> PathBuf {
+    let found = out_dir().join(path);
+    if !found.exists() {
+        panic!("template {:?} not found in OUT_DIR, tried:\n  {}", path, found.display());
     }
+    found
+}
 
-    let mut fs_abs_path = root.clone();
-    let path = Path::new(path);
-    fs_abs_path.push(Path::new(path));
-    if fs_abs_path.exists() {
-        path.to_owned()
-    } else {
-        panic!(format!("template {:?} not found at {:?}", path.to_str().unwrap(), fs_abs_path));
+pub fn out_dir() -> PathBuf {
+    match env::var_os("OUT_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => panic!("OUT_DIR is not set; using templates from OUT_DIR requires a build script"),
+    }
+}
+
+pub fn find_template_from_path(path: &str, start_at: Option<&Path>, config: &Config) -> PathBuf {
+    match find_template(path, start_at, config) {
+        Some(found) => found,
+        None => {
+            let tried = candidates(path, start_at, config).iter()
+                .map(|p| format!("\n  {}", p.display()))
+                .collect::<String>();
+            panic!("template {:?} not found, tried:{}", path, tried)
+        },
+    }
+}
+
+pub fn find_template(path: &str, start_at: Option<&Path>, config: &Config) -> Option<PathBuf> {
+    candidates(path, start_at, config).into_iter().find(|p| p.exists())
+}
+
+// Lists the locations where a template may be found, in order of preference.
+// Paths starting with `@name/` refer to the templates exported by the crate
+// `name`. Otherwise, a path relative to the template at `start_at` comes
+// first, and is looked up in each template directory in turn so that
+// templates in earlier directories can override those in later ones. The path
+// is then looked up in each template directory as is. Templates of other
+// crates are only looked up in the directories of the same crate.
+fn candidates(path: &str, start_at: Option<&Path>, config: &Config) -> Vec<PathBuf> {
+    if path.starts_with('@') {
+        let (name, rest) = match path[1..].find('/') {
+            Some(pos) => (&path[1..pos + 1], &path[pos + 2..]),
+            None => panic!("invalid template path {:?}, expected '@crate/path'", path),
+        };
+        return match config.crates.get(name) {
+            Some(dirs) => dirs.iter().map(|dir| dir.join(rest)).collect(),
+            None => panic!("no templates exported by crate '{}' found, as used in {:?}; \
+                            import them with askama::import_templates()", name, path),
+        };
+    }
+
+    let mut dirs: &[PathBuf] = &config.dirs;
+    let mut found = Vec::new();
+    if let Some(start_at) = start_at {
+        let roots = iter::once(&config.dirs).chain(config.crates.values()).map(|v| v.as_slice());
+        match roots.filter_map(|root| relative_to(start_at, root)).next() {
+            Some((root, rel)) => {
+                dirs = root;
+                let rel = rel.with_file_name(path);
+                found.extend(dirs.iter().map(|dir| dir.join(&rel)));
+            },
+            None => found.push(start_at.with_file_name(path)),
+        }
+    }
+    for candidate in dirs.iter().map(|dir| dir.join(path)) {
+        if !found.contains(&candidate) {
+            found.push(candidate);
+        }
     }
+    found
 }
 
-pub fn template_dir() -> PathBuf {
-    let mut path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
-    path.push("templates");
-    path
+// Returns the path of the template at `path` relative to the first of `dirs`
+// containing it, along with `dirs`.
+fn relative_to<'a>(path: &'a Path, dirs: &'a [PathBuf]) -> Option<(&'a [PathBuf], &'a Path)> {
+    dirs.iter().filter_map(|dir| path.strip_prefix(dir).ok()).next().map(|rel| (dirs, rel))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{find_template_from_path, get_template_source};
-    use super::Path;
+    use super::{find_template, find_template_from_path, get_template_source};
+    use super::{Config, PathBuf};
+
+    fn template(path: &str) -> PathBuf {
+        Config::new("").dirs[0].join(path)
+    }
 
     #[test]
     fn get_source() {
-        assert_eq!(get_template_source(Path::new("sub/b.html")), "bar");
+        assert_eq!(get_template_source(&template("sub/b.html")), "bar");
     }
 
     #[test]
     fn find_absolute() {
-        let path = find_template_from_path("sub/b.html", Some(Path::new("a.html")));
-        assert_eq!(path, Path::new("sub/b.html"));
+        let config = Config::new("");
+        let path = find_template_from_path("sub/b.html", Some(&template("a.html")), &config);
+        assert_eq!(path, template("sub/b.html"));
     }
 
     #[test]
     #[should_panic]
     fn find_relative_nonexistent() {
-        find_template_from_path("b.html", Some(Path::new("a.html")));
+        let config = Config::new("");
+        find_template_from_path("b.html", Some(&template("a.html")), &config);
+    }
+
+    #[test]
+    fn find_missing() {
+        let config = Config::new("");
+        assert_eq!(find_template("b.html", Some(&template("a.html")), &config), None);
     }
 
     #[test]
     fn find_relative() {
-        let path = find_template_from_path("c.html", Some(Path::new("sub/b.html")));
-        assert_eq!(path, Path::new("sub/c.html"));
+        let config = Config::new("");
+        let path = find_template_from_path("c.html", Some(&template("sub/b.html")), &config);
+        assert_eq!(path, template("sub/c.html"));
     }
 
     #[test]
     fn find_relative_sub() {
-        let path = find_template_from_path("sub1/d.html", Some(Path::new("sub/b.html")));
-        assert_eq!(path, Path::new("sub/sub1/d.html"));
+        let config = Config::new("");
+        let path = find_template_from_path("sub1/d.html", Some(&template("sub/b.html")), &config);
+        assert_eq!(path, template("sub/sub1/d.html"));
+    }
+
+    #[test]
+    #[should_panic(expected = "tried:")]
+    fn find_missing_message() {
+        find_template_from_path("x.html", Some(&template("sub/b.html")), &Config::new(""));
+    }
+
+    #[test]
+    fn find_in_crate() {
+        let mut config = Config::new("[general]\ndirs = [\"templates/sub/sub1\"]");
+        config.crates.insert("theme".to_owned(), vec![template("sub")]);
+        let path = find_template_from_path("@theme/b.html", None, &config);
+        assert_eq!(path, template("sub/b.html"));
+        let start_at = template("sub/b.html");
+        let path = find_template_from_path("sub1/d.html", Some(&start_at), &config);
+        assert_eq!(path, template("sub/sub1/d.html"));
+        assert_eq!(find_template("d.html", Some(&start_at), &config), None);
+        assert_eq!(find_template("@theme/a.html", None, &config), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "no templates exported by crate 'theme'")]
+    fn find_in_unknown_crate() {
+        find_template("@theme/b.html", None, &Config::new(""));
+    }
+
+    #[test]
+    fn find_in_dirs() {
+        let config = Config::new("[general]\ndirs = [\"templates/sub\", \"templates\"]");
+        assert_eq!(find_template_from_path("b.html", None, &config), template("sub/b.html"));
+        assert_eq!(find_template_from_path("a.html", None, &config), template("a.html"));
+        let start_at = template("sub/sub1/d.html");
+        let path = find_template_from_path("c.html", Some(&start_at), &config);
+        assert_eq!(path, template("sub/c.html"));
     }
 }
@@ -0,0 +1,129 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+
+
+/// The named content stacks of a template being rendered
+///
+/// Fragments are added to a stack with `{% push "name" %}` and written out
+/// where the template has `{% stack "name" %}`. The stacks are shared with
+/// the components rendered by the template, so their fragments end up in the
+/// same place.
+#[derive(Default)]
+pub struct Stacks {
+    stacks: RefCell<HashMap<String, Vec<String>>>,
+    once: RefCell<HashSet<String>>,
+}
+
+impl Stacks {
+    pub fn new() -> Stacks {
+        Stacks::default()
+    }
+
+    /// Adds a fragment to the end of the named stack.
+    pub fn push(&self, name: &str, fragment: String) {
+        self.stacks.borrow_mut().entry(name.to_owned()).or_insert_with(Vec::new).push(fragment);
+    }
+
+    /// Returns whether the given fragment is seen for the first time, for
+    /// `{% once %}` blocks.
+    pub fn once(&self, fragment: &str) -> bool {
+        self.once.borrow_mut().insert(fragment.to_owned())
+    }
+
+    /// Writes all fragments pushed to the named stack.
+    pub fn write_stack(&self, name: &str, writer: &mut Write) -> fmt::Result {
+        if let Some(fragments) = self.stacks.borrow().get(name) {
+            for fragment in fragments {
+                writer.write_str(fragment)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Buffers the output of a template containing `{% stack %}` tags, so that
+/// fragments pushed after a stack tag still end up in that stack.
+#[derive(Default)]
+pub struct StackBuffer {
+    parts: RefCell<Vec<Part>>,
+}
+
+enum Part {
+    Text(String),
+    Stack(String),
+}
+
+impl StackBuffer {
+    pub fn new() -> StackBuffer {
+        StackBuffer::default()
+    }
+
+    /// Returns a writer appending to the buffered output.
+    pub fn writer(&self) -> StackWriter {
+        StackWriter { buffer: self }
+    }
+
+    /// Marks the place where the named stack is to be written.
+    pub fn mark(&self, name: &str) {
+        self.parts.borrow_mut().push(Part::Stack(name.to_owned()));
+    }
+
+    /// Writes the buffered output, with the stacks filled in.
+    pub fn write_into(&self, writer: &mut Write, stacks: &Stacks) -> fmt::Result {
+        for part in self.parts.borrow().iter() {
+            match *part {
+                Part::Text(ref text) => writer.write_str(text)?,
+                Part::Stack(ref name) => stacks.write_stack(name, writer)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A writer appending to a `StackBuffer`, returned by `StackBuffer::writer()`.
+pub struct StackWriter<'a> {
+    buffer: &'a StackBuffer,
+}
+
+impl<'a> Write for StackWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut parts = self.buffer.parts.borrow_mut();
+        if let Some(&mut Part::Text(ref mut text)) = parts.last_mut() {
+            text.push_str(s);
+            return Ok(());
+        }
+        parts.push(Part::Text(s.to_owned()));
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_buffer() {
+        let stacks = Stacks::new();
+        let buffer = StackBuffer::new();
+        buffer.writer().write_str("<head>").unwrap();
+        buffer.mark("scripts");
+        buffer.writer().write_str("</head>").unwrap();
+        stacks.push("scripts", "<a>".to_owned());
+        stacks.push("styles", "<b>".to_owned());
+        stacks.push("scripts", "<c>".to_owned());
+
+        let mut out = String::new();
+        buffer.write_into(&mut out, &stacks).unwrap();
+        assert_eq!(out, "<head><a><c></head>");
+    }
+
+    #[test]
+    fn test_once() {
+        let stacks = Stacks::new();
+        assert!(stacks.once("<a>"));
+        assert!(stacks.once("<b>"));
+        assert!(!stacks.once("<a>"));
+    }
+}
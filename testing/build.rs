@@ -1,5 +1,16 @@
 extern crate askama;
 
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
 fn main() {
     askama::rerun_if_templates_changed();
+    askama::import_templates();
+
+    // Generate some templates, to test using templates from `OUT_DIR`
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::create_dir_all(out_dir.join("generated")).unwrap();
+    fs::write(out_dir.join("icons.html"), "<i class=\"icon-{{ name }}\"></i>").unwrap();
+    fs::write(out_dir.join("generated/swatch.html"), "<b style=\"color: {{ color }}\"></b>").unwrap();
 }
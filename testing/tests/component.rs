@@ -0,0 +1,37 @@
+#[macro_use]
+extern crate askama;
+
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "card.html")]
+struct Card<'a> {
+    title: &'a str,
+    slot: askama::PreEscaped<String>,
+}
+
+struct Post {
+    title: String,
+    body: String,
+}
+
+#[derive(Template)]
+#[template(path = "component.html")]
+struct ComponentTemplate {
+    posts: Vec<Post>,
+}
+
+#[test]
+fn test_component() {
+    let t = ComponentTemplate {
+        posts: vec![
+            Post { title: "<Foo>".into(), body: "Bar & baz".into() },
+            Post { title: "Qux".into(), body: "Quux".into() },
+        ],
+    };
+    assert_eq!(
+        t.render().unwrap(),
+        "\n<div><h2>&lt;Foo&gt;</h2><p>Bar &amp; baz</p></div>\n\n\
+         <div><h2>Qux</h2><p>Quux</p></div>\n"
+    );
+}
@@ -0,0 +1,102 @@
+#[macro_use]
+extern crate askama;
+
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "brackets.txt", syntax = "brackets")]
+struct SyntaxTemplate<'a> {
+    items: &'a [&'a str],
+}
+
+#[test]
+fn test_syntax() {
+    let t = SyntaxTemplate { items: &["a", "b"] };
+    assert_eq!(t.render().unwrap(), "a \nb \n");
+}
+
+#[derive(Template)]
+#[template(source = "{% include \"list.btxt\" %}{{ items.len() }}", ext = "txt")]
+struct MixedSyntaxTemplate<'a> {
+    items: &'a [&'a str],
+}
+
+#[test]
+fn test_mixed_syntax() {
+    let t = MixedSyntaxTemplate { items: &["a", "b"] };
+    assert_eq!(t.render().unwrap(), "a,b,{{ x }}2");
+}
+
+#[derive(Template)]
+#[template(source = "<text>{{ s }}</text>", ext = "svg")]
+struct EscaperTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn test_escaper() {
+    let t = EscaperTemplate { s: "<a href='#'>" };
+    assert_eq!(t.render().unwrap(), "<text>&lt;a href=&apos;#&apos;&gt;</text>");
+}
+
+#[derive(Template)]
+#[template(source = "<ul>\n  {% for item in items %}\n  <li>{{ item }}</li>\n  {% endfor %}\n</ul>",
+           ext = "txt", whitespace = "suppress")]
+struct WhitespaceTemplate<'a> {
+    items: &'a [&'a str],
+}
+
+#[test]
+fn test_whitespace() {
+    let t = WhitespaceTemplate { items: &["a", "b"] };
+    assert_eq!(t.render().unwrap(), "<ul><li>a</li><li>b</li></ul>");
+}
+
+#[derive(Template)]
+#[template(path = "theme-child.html")]
+struct ThemeTemplate<'a> {
+    title: &'a str,
+}
+
+#[test]
+fn test_template_dirs() {
+    let t = ThemeTemplate { title: "Hi" };
+    assert_eq!(t.render().unwrap(), "<h1>Hi</h1>\n<p>custom footer</p>");
+}
+
+#[derive(Template)]
+#[template(path = "theme-page.html")]
+struct ThemePageTemplate<'a> {
+    title: &'a str,
+}
+
+#[test]
+fn test_crate_templates() {
+    let t = ThemePageTemplate { title: "Hi" };
+    assert_eq!(t.render().unwrap(), "<h1>Hi <span class=\"badge\">new</span></h1>\n\
+                                     <footer>theme</footer>");
+}
+
+#[derive(Template)]
+#[template(path = "icons.html", in_out_dir)]
+struct OutDirTemplate<'a> {
+    name: &'a str,
+}
+
+#[test]
+fn test_in_out_dir() {
+    let t = OutDirTemplate { name: "star" };
+    assert_eq!(t.render().unwrap(), "<i class=\"icon-star\"></i>");
+}
+
+#[derive(Template)]
+#[template(source = "{% include \"swatch.html\" %}", ext = "html")]
+struct OutDirIncludeTemplate<'a> {
+    color: &'a str,
+}
+
+#[test]
+fn test_out_dir_template_dir() {
+    let t = OutDirIncludeTemplate { color: "red" };
+    assert_eq!(t.render().unwrap(), "<b style=\"color: red\"></b>");
+}
@@ -26,6 +26,158 @@ fn filter_escape() {
 }
 
 
+#[derive(Template)]
+#[template(path = "contexts.html")]
+struct ContextsTemplate<'a> {
+    s: &'a str,
+    url: &'a str,
+    color: &'a str,
+    bold: bool,
+}
+
+#[test]
+fn escape_contexts() {
+    let t = ContextsTemplate {
+        s: "a'b <c>",
+        url: "javascript:alert(1)",
+        color: "red;}",
+        bold: true,
+    };
+    assert_eq!(
+        t.render().unwrap(),
+        "<a href=\"#\" title=\"a&#x27;b &lt;c&gt;\" \
+         onclick=\"alert('a\\u0027b \\u003cc\\u003e')\">a&#x27;b &lt;c&gt;</a>\n\
+         <a href=\"/search?q=a%27b%20%3Cc%3E\" style=\"font-weight: bold\">\
+         javascript:alert(1)</a>\n\
+         <script>var s = \"a\\u0027b \\u003cc\\u003e\";</script>\n\
+         <p style=\"color: red\\3b \\7d \">a&#x27;b &lt;c&gt;</p>"
+    );
+}
+
+
+#[derive(Template)]
+#[template(source = "<a href=\"{{ url ~ \"#top\"|safe }}\" onclick=\"f('{{ s|e }}')\">",
+           ext = "html")]
+struct ContextsExplicitTemplate<'a> {
+    s: &'a str,
+    url: &'a str,
+}
+
+#[test]
+fn escape_contexts_explicit() {
+    let t = ContextsExplicitTemplate { s: "a'b", url: "javascript:alert(1)" };
+    assert_eq!(t.render().unwrap(), "<a href=\"##top\" onclick=\"f('a\\u0027b')\">");
+}
+
+
+#[derive(Template)]
+#[template(source = "<a href=\" {{ url }}\"{% if bold %} class=\"b\"{% endif %}>", ext = "html")]
+struct ContextsUrlTemplate<'a> {
+    url: &'a str,
+    bold: bool,
+}
+
+#[test]
+fn escape_contexts_url_leading_whitespace() {
+    let t = ContextsUrlTemplate { url: "javascript:alert(1)", bold: true };
+    assert_eq!(t.render().unwrap(), "<a href=\" #\" class=\"b\">");
+    let t = ContextsUrlTemplate { url: "/home", bold: false };
+    assert_eq!(t.render().unwrap(), "<a href=\" /home\">");
+}
+
+
+#[derive(Template)]
+#[template(path = "autoescape.html")]
+struct AutoEscapeTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn escape_autoescape() {
+    let t = AutoEscapeTemplate { s: "<'>" };
+    assert_eq!(
+        t.render().unwrap(),
+        "\n&lt;&#x27;&gt;\n<'> [<'>]\n'\\u003c\\u0027\\u003e'"
+    );
+}
+
+
+struct Fraction(u8, u8);
+
+impl ::std::fmt::Display for Fraction {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}/{}", self.0, self.1)
+    }
+}
+
+impl askama::HtmlSafe for Fraction {}
+
+#[derive(Template)]
+#[template(source = "{{ f }} {{ s }} {{ n * 2.0 }}", ext = "html")]
+struct HtmlSafeTemplate<'a> {
+    f: Fraction,
+    s: &'a str,
+    n: f64,
+}
+
+#[test]
+fn escape_html_safe() {
+    let t = HtmlSafeTemplate { f: Fraction(1, 2), s: "1/2", n: 0.25 };
+    assert_eq!(t.render().unwrap(), "1/2 1&#x2f;2 0.5");
+}
+
+#[derive(Template)]
+#[template(source = "<p style=\"width: {{ f }}\">{{ f }}</p>", ext = "html")]
+struct HtmlSafeContextTemplate {
+    f: Fraction,
+}
+
+#[test]
+fn escape_html_safe_context() {
+    let t = HtmlSafeContextTemplate { f: Fraction(1, 2) };
+    assert_eq!(t.render().unwrap(), "<p style=\"width: 1\\2f 2\">1/2</p>");
+}
+
+
+#[derive(Template)]
+#[template(source = "var s = '{{ s }}'; cd {{ s|e }}", ext = "js", escape = "js")]
+struct JsEscapeTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn filter_escape_js() {
+    let t = JsEscapeTemplate { s: "it's </script>" };
+    assert_eq!(
+        t.render().unwrap(),
+        "var s = 'it\\u0027s \\u003c/script\\u003e'; cd it\\u0027s \\u003c/script\\u003e"
+    );
+}
+
+
+struct Shout;
+
+impl askama::Escaper for Shout {
+    fn write_escaped<W>(&self, mut fmt: W, string: &str) -> ::std::fmt::Result
+        where W: ::std::fmt::Write
+    {
+        fmt.write_str(&string.to_uppercase())
+    }
+}
+
+#[derive(Template)]
+#[template(source = "{{ s }} {{ s|safe }}", ext = "txt", escape = "Shout")]
+struct CustomEscapeTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn filter_escape_custom() {
+    let t = CustomEscapeTemplate { s: "foo" };
+    assert_eq!(t.render().unwrap(), "FOO foo");
+}
+
+
 #[derive(Template)]
 #[template(path = "format.html", escape = "none")]
 struct FormatTemplate<'a> {
@@ -150,3 +302,16 @@ fn test_filter_let_filter() {
     let t = FilterLetFilterTemplate { foo: " bar ".to_owned(), baz: Baz {} };
     assert_eq!(t.render().unwrap(), "BAR");
 }
+
+
+#[derive(Template)]
+#[template(source = "[{{ s|indent(2) }}] [{{ s|indent(2, true) }}]", ext = "txt")]
+struct IndentTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn test_indent() {
+    let t = IndentTemplate { s: "foo\nbar" };
+    assert_eq!(t.render().unwrap(), "[foo\n  bar] [  foo\n  bar]");
+}
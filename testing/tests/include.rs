@@ -15,3 +15,30 @@ fn test_include() {
     let s = IncludeTemplate { strs: &strs };
     assert_eq!(s.render().unwrap(), "\n  INCLUDED: foo\n  INCLUDED: bar")
 }
+
+#[derive(Template)]
+#[template(path = "include-with.html")]
+struct IncludeWithTemplate<'a> {
+    entries: &'a [&'a str],
+    item: &'a str,
+}
+
+#[test]
+fn test_include_with() {
+    let entries = vec!["foo", "bar"];
+    let s = IncludeWithTemplate { entries: &entries, item: "baz" };
+    assert_eq!(s.render().unwrap(), "\n  [foo*]\n  [bar*]\n\nbaz")
+}
+
+#[derive(Template)]
+#[template(path = "include-indent.txt")]
+struct IncludeIndentTemplate<'a> {
+    b: usize,
+    lines: &'a str,
+}
+
+#[test]
+fn test_include_indent() {
+    let s = IncludeIndentTemplate { b: 2, lines: "- x\n- y" };
+    assert_eq!(s.render().unwrap(), "fields:\n  a: 1\n  b: 2\nlines:\n    - x\n    - y")
+}
@@ -76,3 +76,112 @@ fn test_nested_blocks() {
     let t = NestedChildTemplate { _parent: NestedBaseTemplate {} };
     assert_eq!(t.render().unwrap(), "\ndurpy\n");
 }
+
+
+#[derive(Template)]
+#[template(path = "deep-base.html")]
+struct DeepBaseTemplate<'a> {
+    title: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "deep-mid.html")]
+struct DeepMidTemplate<'a> {
+    _parent: DeepBaseTemplate<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "deep-kid.html")]
+struct DeepKidTemplate<'a> {
+    _parent: DeepMidTemplate<'a>,
+}
+
+#[test]
+fn test_deep_base() {
+    let t = DeepBaseTemplate { title: "base" };
+    assert_eq!(t.render().unwrap(), "<base>\nbase");
+}
+
+#[test]
+fn test_deep_mid() {
+    let t = DeepMidTemplate { _parent: DeepBaseTemplate { title: "mid" } };
+    assert_eq!(t.render().unwrap(), "<mid><mid>\nmid(base)");
+}
+
+#[test]
+fn test_deep_kid() {
+    let t = DeepKidTemplate {
+        _parent: DeepMidTemplate { _parent: DeepBaseTemplate { title: "kid" } },
+    };
+    assert_eq!(t.render().unwrap(), "<kid><mid><kid>\n[mid(base)]");
+}
+
+
+#[derive(Template)]
+#[template(path = "child.html")]
+struct ChildOnlyTemplate<'a> {
+    title: &'a str,
+}
+
+#[test]
+fn test_extends_without_parent() {
+    let t = ChildOnlyTemplate { title: "Baz" };
+    assert_eq!(
+        t.render().unwrap(),
+        "Baz\n(Baz) Content goes here\nFoo\nCopyright 2017"
+    );
+}
+
+
+struct Layout<'a> {
+    title: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "child.html")]
+struct MarkedParentTemplate<'a> {
+    #[template(parent)]
+    layout: Layout<'a>,
+}
+
+#[test]
+fn test_marked_parent() {
+    let t = MarkedParentTemplate { layout: Layout { title: "Qux" } };
+    assert_eq!(
+        t.render().unwrap(),
+        "Qux\n(Qux) Content goes here\nFoo\nCopyright 2017"
+    );
+}
+
+
+#[test]
+fn test_render_block() {
+    let t = DeepKidTemplate {
+        _parent: DeepMidTemplate { _parent: DeepBaseTemplate { title: "kid" } },
+    };
+    assert_eq!(t.render_block("head").unwrap(), "<kid><mid><kid>");
+    assert_eq!(t.render_block("content").unwrap(), "mid(base)");
+    assert_eq!(t.render_block_body().unwrap(), "[mid(base)]");
+    match t.render_block("foo") {
+        Err(askama::Error(askama::ErrorKind::BlockNotFound(ref name), _)) => {
+            assert_eq!(name, "foo");
+        },
+        _ => panic!("expected missing block error"),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "required-base.html")]
+struct RequiredBaseTemplate {}
+
+#[derive(Template)]
+#[template(path = "required-child.html")]
+struct RequiredChildTemplate<'a> {
+    title: &'a str,
+}
+
+#[test]
+fn test_required_block() {
+    let t = RequiredChildTemplate { title: "Page" };
+    assert_eq!(t.render().unwrap(), "Page: none");
+}
@@ -26,3 +26,39 @@ fn test_import() {
     let t = ImportTemplate { s: "foo" };
     assert_eq!(t.render().unwrap(), "foo foo foo");
 }
+
+#[derive(Template)]
+#[template(path = "macro-child.html")]
+struct MacroChildTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn test_macro_from_base() {
+    let t = MacroChildTemplate { s: "foo" };
+    assert_eq!(t.render().unwrap(), "\nhello foo");
+}
+
+#[derive(Template)]
+#[template(path = "macro-include.html")]
+struct MacroIncludeTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn test_macro_from_include() {
+    let t = MacroIncludeTemplate { s: "foo" };
+    assert_eq!(t.render().unwrap(), "foo!");
+}
+
+#[derive(Template)]
+#[template(path = "macro-import-base.html")]
+struct MacroImportBaseTemplate<'a> {
+    s: &'a str,
+}
+
+#[test]
+fn test_import_macro_from_base() {
+    let t = MacroImportBaseTemplate { s: "foo" };
+    assert_eq!(t.render().unwrap(), "hello foo");
+}
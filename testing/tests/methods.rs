@@ -41,3 +41,54 @@ fn test_nested() {
     };
     assert_eq!(t.render().unwrap(), "bar foo");
 }
+
+
+#[derive(Template)]
+#[template(source = "{{ self.parse(s)? }}", ext = "txt")]
+struct TryMethodTemplate<'a> {
+    s: &'a str,
+}
+
+impl<'a> TryMethodTemplate<'a> {
+    fn parse(&self, s: &str) -> Result<u32, std::num::ParseIntError> {
+        s.parse()
+    }
+}
+
+#[test]
+fn test_try_method() {
+    let t = TryMethodTemplate { s: "42" };
+    assert_eq!(t.render().unwrap(), "42");
+
+    let t = TryMethodTemplate { s: "foo" };
+    match t.render() {
+        Err(askama::Error(askama::ErrorKind::Custom(err), _)) => {
+            assert_eq!(err.to_string(), "invalid digit found in string");
+        },
+        _ => panic!("expected custom error"),
+    }
+}
+
+
+struct Parsed {
+    value: u32,
+}
+
+#[derive(Template)]
+#[template(source = "{{ self.parse(s)?.value }}", ext = "txt")]
+struct TryFieldTemplate<'a> {
+    s: &'a str,
+}
+
+impl<'a> TryFieldTemplate<'a> {
+    fn parse(&self, s: &str) -> Result<Parsed, std::num::ParseIntError> {
+        s.parse().map(|value| Parsed { value })
+    }
+}
+
+#[test]
+fn test_try_field() {
+    let t = TryFieldTemplate { s: "42" };
+    assert_eq!(t.render().unwrap(), "42");
+    assert!(TryFieldTemplate { s: "foo" }.render().is_err());
+}
@@ -42,3 +42,31 @@ fn test_precedence() {
     let t = PrecedenceTemplate {};
     assert_eq!(t.render().unwrap(), "6".repeat(7));
 }
+
+
+#[derive(Template)]
+#[template(source = "{{ a ~ \"-\" ~ b }} {{ a ~ b|safe }} {{ (a ~ 1) == \"<a>1\" }}", ext = "html")]
+struct ConcatTemplate<'a> {
+    a: &'a str,
+    b: &'a str,
+}
+
+#[test]
+fn test_concat() {
+    let t = ConcatTemplate { a: "<a>", b: "<b>" };
+    assert_eq!(t.render().unwrap(), "&lt;a&gt;-&lt;b&gt; &lt;a&gt;<b> true");
+}
+
+
+#[derive(Template)]
+#[template(source = "{{ a.starts_with(&b) }} {{ &a == &b }} {{ (&a).len() }}", ext = "txt")]
+struct ReferenceTemplate {
+    a: String,
+    b: String,
+}
+
+#[test]
+fn test_reference() {
+    let t = ReferenceTemplate { a: "foo".to_string(), b: "fo".to_string() };
+    assert_eq!(t.render().unwrap(), "true false 3");
+}
@@ -141,6 +141,104 @@ fn test_option() {
 }
 
 
+struct Profile {
+    display_name: String,
+    website: Option<String>,
+}
+
+struct User {
+    name: String,
+    profile: Option<Profile>,
+}
+
+#[derive(Template)]
+#[template(source = "{{ user&.profile&.display_name ?? \"Anonymous\" }} \
+                     {{ user&.name.len() ?? 0 }} {{ user&.name|default(\"-\") }}",
+           ext = "txt")]
+struct OptionalChainTemplate {
+    user: Option<User>,
+}
+
+#[test]
+fn test_optional_chain() {
+    let t = OptionalChainTemplate {
+        user: Some(User {
+            name: "foo".to_string(),
+            profile: Some(Profile { display_name: "Foo".to_string(), website: None }),
+        }),
+    };
+    assert_eq!(t.render().unwrap(), "Foo 3 foo");
+    let t = OptionalChainTemplate {
+        user: Some(User { name: "bar".to_string(), profile: None }),
+    };
+    assert_eq!(t.render().unwrap(), "Anonymous 3 bar");
+    let t = OptionalChainTemplate { user: None };
+    assert_eq!(t.render().unwrap(), "Anonymous 0 -");
+}
+
+#[derive(Template)]
+#[template(source = "{{ user&.profile&.website ?? \"none\" }}", ext = "txt")]
+struct OptionalLastTemplate {
+    user: Option<User>,
+}
+
+#[test]
+fn test_optional_chain_last() {
+    let profile = |website: Option<&str>| Profile {
+        display_name: "Foo".to_string(),
+        website: website.map(|s| s.to_string()),
+    };
+    let user = |profile| User { name: "foo".to_string(), profile };
+    let t = OptionalLastTemplate { user: Some(user(Some(profile(Some("foo.org"))))) };
+    assert_eq!(t.render().unwrap(), "foo.org");
+    let t = OptionalLastTemplate { user: Some(user(Some(profile(None)))) };
+    assert_eq!(t.render().unwrap(), "none");
+    let t = OptionalLastTemplate { user: Some(user(None)) };
+    assert_eq!(t.render().unwrap(), "none");
+}
+
+
+#[derive(Template)]
+#[template(path = "if-let.html")]
+struct IfLetTemplate<'a> {
+    name: Option<&'a str>,
+    fallback: Option<u32>,
+}
+
+#[test]
+fn test_if_let() {
+    let t = IfLetTemplate { name: Some("foo"), fallback: None };
+    assert_eq!(t.render().unwrap(), "hello, foo");
+    let t = IfLetTemplate { name: None, fallback: Some(0) };
+    assert_eq!(t.render().unwrap(), "zero");
+    let t = IfLetTemplate { name: None, fallback: Some(42) };
+    assert_eq!(t.render().unwrap(), "42");
+    let t = IfLetTemplate { name: None, fallback: None };
+    assert_eq!(t.render().unwrap(), "nobody");
+}
+
+
+struct Person<'a> {
+    name: &'a str,
+    nick: Option<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "let-else.html")]
+struct LetElseTemplate<'a> {
+    user: Option<Person<'a>>,
+}
+
+#[test]
+fn test_let_else() {
+    let t = LetElseTemplate { user: None };
+    assert_eq!(t.render().unwrap(), "nobody");
+    let t = LetElseTemplate { user: Some(Person { name: "Foo Bar", nick: None }) };
+    assert_eq!(t.render().unwrap(), "\nFoo Bar");
+    let t = LetElseTemplate { user: Some(Person { name: "Foo Bar", nick: Some("foo") }) };
+    assert_eq!(t.render().unwrap(), "\nhello, foo");
+}
+
 #[derive(Template)]
 #[template(path = "generics.html")]
 struct GenericsTemplate<T: std::fmt::Display, U = u8>
@@ -169,6 +267,52 @@ fn test_composition() {
 }
 
 
+#[derive(Template)]
+#[template(source = "<b>{{ s }}</b>", ext = "html")]
+struct BoldTemplate<'a> {
+    s: &'a str,
+}
+
+#[derive(Template)]
+#[template(source = "{{ inner }} {{ raw }} {{ s }}", ext = "html")]
+struct MarkupTemplate<'a> {
+    inner: BoldTemplate<'a>,
+    raw: askama::PreEscaped<&'a str>,
+    s: &'a str,
+}
+
+#[test]
+fn test_markup() {
+    let t = MarkupTemplate {
+        inner: BoldTemplate { s: "a & b" },
+        raw: askama::PreEscaped("<i>x</i>"),
+        s: "<i>x</i>",
+    };
+    assert_eq!(t.render().unwrap(), "<b>a &amp; b</b> <i>x</i> &lt;i&gt;x&lt;&#x2f;i&gt;");
+}
+
+#[derive(Template)]
+#[template(source = "<script>var s = \"{{ inner }}\";</script><a href=\"{{ raw }}\">",
+           ext = "html")]
+struct MarkupContextTemplate<'a> {
+    inner: BoldTemplate<'a>,
+    raw: askama::PreEscaped<&'a str>,
+}
+
+#[test]
+fn test_markup_context() {
+    let t = MarkupContextTemplate {
+        inner: BoldTemplate { s: "a & b" },
+        raw: askama::PreEscaped("javascript:alert(1)"),
+    };
+    assert_eq!(
+        t.render().unwrap(),
+        "<script>var s = \"\\u003cb\\u003ea \\u0026amp; b\\u003c/b\\u003e\";</script>\
+         <a href=\"#\">"
+    );
+}
+
+
 #[derive(PartialEq, Eq)]
 enum Alphabet {
     Alpha,
@@ -0,0 +1,29 @@
+#[macro_use]
+extern crate askama;
+
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "stack-widget.html")]
+struct Widget {}
+
+#[derive(Template)]
+#[template(path = "stack-child.html")]
+struct StackTemplate<'a> {
+    items: &'a [usize],
+}
+
+#[test]
+fn test_stacks() {
+    let t = StackTemplate { items: &[1, 2] };
+    assert_eq!(
+        t.render().unwrap(),
+        "<head><script src=\"item.js\"></script><script src=\"widget.js\"></script></head>\n\
+         12\n[widget]"
+    );
+}
+
+#[test]
+fn test_stacks_standalone() {
+    assert_eq!(Widget {}.render().unwrap(), "[widget]");
+}
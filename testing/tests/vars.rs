@@ -28,3 +28,29 @@ fn test_let_decl() {
     let t = LetDeclTemplate { cond: false, s: "bar" };
     assert_eq!(t.render().unwrap(), "bar");
 }
+
+
+#[derive(Template)]
+#[template(source = "{{ r#type }} {% let größe = self.r#type.len() %}{{ größe }}", ext = "txt")]
+struct RawIdentifierTemplate<'a> {
+    r#type: &'a str,
+}
+
+#[test]
+fn test_raw_identifier() {
+    let t = RawIdentifierTemplate { r#type: "foo" };
+    assert_eq!(t.render().unwrap(), "foo 3");
+}
+
+
+#[derive(Template)]
+#[template(path = "let-mut.html")]
+struct LetMutTemplate {
+    items: Vec<usize>,
+}
+
+#[test]
+fn test_let_mut() {
+    let t = LetMutTemplate { items: vec![1, 2, 3] };
+    assert_eq!(t.render().unwrap(), "6\n3/3");
+}
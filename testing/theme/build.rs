@@ -0,0 +1,5 @@
+extern crate askama;
+
+fn main() {
+    askama::export_templates();
+}
@@ -0,0 +1,2 @@
+//! Templates shared with `askama_testing`, to test using templates exported
+//! by a dependency